@@ -0,0 +1,14 @@
+-- API keys used by scripts and service-to-service callers that can't use a browser login
+
+CREATE TABLE IF NOT EXISTS api_key
+(
+    id uuid NOT NULL DEFAULT gen_random_uuid(),
+    name varchar(64) NOT NULL,
+    prefix varchar(16) UNIQUE NOT NULL, -- The non-secret part of the key, safe to display to identify the key
+    key_hash varchar(64) UNIQUE NOT NULL, -- Hex encoded SHA-256 of the full key, the key itself is never stored
+    scopes text[] NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    last_used_at timestamptz,
+    revoked_at timestamptz,
+    CONSTRAINT api_key_pkey PRIMARY KEY ("id")
+)
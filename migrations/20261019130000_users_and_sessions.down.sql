@@ -0,0 +1,5 @@
+-- Local users, their linked external identities and login sessions
+DROP TABLE IF EXISTS oidc_login;
+DROP TABLE IF EXISTS user_session;
+DROP TABLE IF EXISTS user_identity;
+DROP TABLE IF EXISTS app_user;
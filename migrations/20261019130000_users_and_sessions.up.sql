@@ -0,0 +1,42 @@
+-- Local users, their linked external identities (i.e. OpenID Connect logins) and login sessions
+
+CREATE TABLE IF NOT EXISTS app_user
+(
+    id uuid NOT NULL DEFAULT gen_random_uuid(),
+    email varchar(254) UNIQUE,
+    name varchar(128),
+    scopes text[] NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    last_login_at timestamptz,
+    CONSTRAINT app_user_pkey PRIMARY KEY ("id")
+);
+
+-- An identity at an external identity provider, identified by the provider's issuer URL and its subject for the user
+CREATE TABLE IF NOT EXISTS user_identity
+(
+    issuer varchar(255) NOT NULL,
+    subject varchar(255) NOT NULL,
+    user_id uuid NOT NULL REFERENCES app_user (id) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    CONSTRAINT user_identity_pkey PRIMARY KEY (issuer, subject)
+);
+
+CREATE TABLE IF NOT EXISTS user_session
+(
+    id uuid NOT NULL DEFAULT gen_random_uuid(),
+    token_hash varchar(64) UNIQUE NOT NULL, -- Hex encoded SHA-256 of the session cookie's token
+    user_id uuid NOT NULL REFERENCES app_user (id) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz NOT NULL,
+    CONSTRAINT user_session_pkey PRIMARY KEY ("id")
+);
+
+-- In-flight logins, holding what we need to verify the identity provider's response when the user is redirected back
+CREATE TABLE IF NOT EXISTS oidc_login
+(
+    state varchar(64) NOT NULL,
+    code_verifier varchar(128) NOT NULL,
+    nonce varchar(64) NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    CONSTRAINT oidc_login_pkey PRIMARY KEY (state)
+);
@@ -0,0 +1,2 @@
+-- Token buckets for rate limiting
+DROP TABLE IF EXISTS rate_limit_bucket;
@@ -0,0 +1,11 @@
+-- Token buckets for rate limiting, when limits are shared across app server instances (i.e. rate_limit_store = "postgres").
+-- UNLOGGED since losing the buckets on a DB crash just resets everyone's limits, and it makes the frequent writes cheaper.
+
+CREATE UNLOGGED TABLE IF NOT EXISTS rate_limit_bucket
+(
+    key varchar(128) NOT NULL,
+    tokens double precision NOT NULL,
+    allowed boolean NOT NULL, -- Whether the last request taken from the bucket was allowed
+    updated_at timestamptz NOT NULL DEFAULT now(),
+    CONSTRAINT rate_limit_bucket_pkey PRIMARY KEY (key)
+)
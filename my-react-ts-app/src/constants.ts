@@ -2,6 +2,18 @@
 // Great article on various approaches: https://semaphoreci.com/blog/constants-layer-javascript
 
 
+// Config the app server injects into index.html at runtime (see the app server's spa::index), so one build works in
+// every environment. Missing when index.html isn't served by the app server, e.g. `npm start`.
+declare global {
+  interface Window {
+    __APP_CONFIG__?: {
+      apiBaseUrl: string,
+      singleSignOn: boolean,
+      features: string[],
+    }
+  }
+}
+
 // API constants
 export const APIConstants = Object.freeze({
   GET: 'GET',
@@ -10,7 +22,8 @@ export const APIConstants = Object.freeze({
   PATCH: 'PATCH',
   DELETE: 'DELETE',
   APPLICATION_JSON_HEADER: 'application/json',
-  BACKEND_BASE_URL: process.env.REACT_APP_BACKEND_BASE_URL, // This is loaded from the .env file at React project root
+  // Falls back to the .env file at React project root, which is baked in at build time
+  BACKEND_BASE_URL: window.__APP_CONFIG__?.apiBaseUrl ?? process.env.REACT_APP_BACKEND_BASE_URL,
 })
 
 export const API_URLS = Object.freeze({
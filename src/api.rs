@@ -1,3 +1,4 @@
 //! Provides utilities for interacting with our service APIs
 pub mod endpoints;
+pub mod errors;
 pub mod resources;
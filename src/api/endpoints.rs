@@ -7,16 +7,123 @@
 /// schema validation.
 use crate::{
     resources::Player,
-    services::{app_server::AppState, search},
+    services::{
+        app_server::AppState,
+        auth::{
+            self, api_keys,
+            oidc::{self, OidcConfig},
+            sessions, Principal, Scope,
+        },
+        db, search,
+        tracing::filters,
+    },
 };
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    Json,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{AppendHeaders, IntoResponse, Redirect, Response},
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
 };
+use std::time::Duration;
+use tracing::Instrument;
 
-use super::resources::SearchRequest;
+use super::{
+    errors::ApiError,
+    resources::{ApiKeyCreateRequest, LogFilterUpdateRequest, LoginCallbackParams, SearchRequest},
+};
+
+/// Returns the routes for all endpoints from our public APIs except the search and admin ones (see [`search_routes`] and
+/// [`admin_routes`]), each guarded by the [`Scope`] it requires. The [`auth::authenticate`] middleware must be layered
+/// on top so the scope checks know who is calling.
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            PLAYERS_API,
+            get(get_players).route_layer(middleware::from_fn_with_state(
+                Scope::PlayersRead,
+                auth::require_scope,
+            )),
+        )
+        .route(
+            build_id_path(PLAYERS_API).as_str(),
+            get(get_player).route_layer(middleware::from_fn_with_state(
+                Scope::PlayersRead,
+                auth::require_scope,
+            )),
+        )
+        .route(
+            PLAYERS_API,
+            put(add_player).route_layer(middleware::from_fn_with_state(
+                Scope::PlayersWrite,
+                auth::require_scope,
+            )),
+        )
+        .route(build_auth_path(LOGIN_PATH).as_str(), get(login))
+        .route(
+            build_auth_path(LOGIN_CALLBACK_PATH).as_str(),
+            get(login_callback),
+        )
+        .route(build_auth_path(LOGOUT_PATH).as_str(), post(logout))
+        .route(
+            build_auth_path(CURRENT_PRINCIPAL_PATH).as_str(),
+            get(get_current_principal),
+        )
+}
+
+/// Returns the routes for our search APIs. They're separate from [`routes`] since they call Search rather than the
+/// database, so they get their own request timeout. The [`auth::authenticate`] middleware must be layered on top.
+pub fn search_routes() -> Router<AppState> {
+    Router::new().route(
+        build_player_search_path().as_str(),
+        post(search_players).route_layer(middleware::from_fn_with_state(
+            Scope::PlayersRead,
+            auth::require_scope,
+        )),
+    )
+}
+
+/// Returns the routes for our admin APIs, which all require the [`Scope::Admin`] scope. They're served with the rest of
+/// our APIs, or only on the admin listener when `admin_server_url` is set. The [`auth::authenticate`] middleware must be
+/// layered on top so the scope checks know who is calling.
+pub fn admin_routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            API_KEYS_ADMIN_API,
+            get(get_api_keys).route_layer(middleware::from_fn_with_state(
+                Scope::Admin,
+                auth::require_scope,
+            )),
+        )
+        .route(
+            API_KEYS_ADMIN_API,
+            put(add_api_key).route_layer(middleware::from_fn_with_state(
+                Scope::Admin,
+                auth::require_scope,
+            )),
+        )
+        .route(
+            build_id_path(API_KEYS_ADMIN_API).as_str(),
+            delete(revoke_api_key).route_layer(middleware::from_fn_with_state(
+                Scope::Admin,
+                auth::require_scope,
+            )),
+        )
+        .route(
+            LOG_FILTERS_ADMIN_API,
+            get(get_log_filters).route_layer(middleware::from_fn_with_state(
+                Scope::Admin,
+                auth::require_scope,
+            )),
+        )
+        .route(
+            build_id_path(LOG_FILTERS_ADMIN_API).as_str(),
+            put(set_log_filter).delete(reset_log_filter).route_layer(
+                middleware::from_fn_with_state(Scope::Admin, auth::require_scope),
+            ),
+        )
+}
 
 // General API constants and utilities
 const ID_PATH: &str = "/:id";
@@ -38,12 +145,11 @@ pub async fn get_players(State(app_state): State<AppState>) -> impl IntoResponse
         "select id, number, name, email, username from player"
     )
     .fetch_all(&app_state.db_pool)
+    .instrument(db::query_span("SELECT player"))
     .await
     {
         Ok(players) => players,
-        Err(err) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(err.to_string())).into_response()
-        }
+        Err(err) => return ApiError::from(err).into_response(),
     };
 
     (StatusCode::OK, Json(players)).into_response()
@@ -60,12 +166,11 @@ pub async fn get_player(
         id
     )
     .fetch_one(&app_state.db_pool)
+    .instrument(db::query_span("SELECT player"))
     .await
     {
         Ok(player) => player,
-        Err(err) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(err.to_string())).into_response()
-        }
+        Err(err) => return ApiError::from(err).into_response(),
     };
 
     (StatusCode::OK, Json(player)).into_response()
@@ -88,18 +193,22 @@ pub async fn add_player(
         player_to_add.email
     )
     .fetch_one(&app_state.db_pool)
+    .instrument(db::query_span("INSERT player"))
     .await
     {
         Ok(new_player) => new_player,
-        Err(err) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(err.to_string())).into_response()
-        }
+        Err(err) => return ApiError::from(err).into_response(),
     };
 
     // Add the newly added player to our search service and index.
     // In the future, this manual approach may be subsituted for a more automatic index-ing strategy such as something
     // integrated in or with Postgres directly (such as log or notification)
-    search::index_player(&app_state.search_client, &new_player).await;
+    search::index_player(
+        &app_state.search_client,
+        &app_state.config.player_search_index,
+        &new_player,
+    )
+    .await;
 
     (StatusCode::CREATED, Json(new_player)).into_response()
 }
@@ -120,13 +229,268 @@ pub async fn search_players(
     State(app_state): State<AppState>,
     Json(term): Json<SearchRequest>,
 ) -> impl IntoResponse {
-    let players = search::player_search(&app_state.search_client, &term.term).await;
+    let players = search::player_search(
+        &app_state.search_client,
+        &app_state.config.player_search_index,
+        &term.term,
+    )
+    .await;
 
     (StatusCode::OK, Json(players)).into_response()
 }
 
 // END: Search API
 
+// BEGIN: API Keys Admin API
+
+/// Base path for our API Keys admin API
+pub const API_KEYS_ADMIN_API: &str = "/api/admin/api-keys";
+
+/// Returns all API keys, including revoked ones
+pub async fn get_api_keys(State(app_state): State<AppState>) -> impl IntoResponse {
+    match api_keys::get_api_keys(&app_state.db_pool).await {
+        Ok(api_keys) => (StatusCode::OK, Json(api_keys)).into_response(),
+        Err(err) => ApiError::from(err).into_response(),
+    }
+}
+
+/// Creates a new API key. The response is the only time the full key is returned.
+pub async fn add_api_key(
+    State(app_state): State<AppState>,
+    Json(api_key_to_add): Json<ApiKeyCreateRequest>,
+) -> impl IntoResponse {
+    match api_keys::create_api_key(
+        &app_state.db_pool,
+        &api_key_to_add.name,
+        &api_key_to_add.scopes,
+    )
+    .await
+    {
+        Ok(created) => (StatusCode::CREATED, Json(created)).into_response(),
+        Err(err) => ApiError::from(err).into_response(),
+    }
+}
+
+/// Revokes an API key by its ID
+pub async fn revoke_api_key(
+    State(app_state): State<AppState>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    match api_keys::revoke_api_key(&app_state.db_pool, id).await {
+        Ok(api_key) => (StatusCode::OK, Json(api_key)).into_response(),
+        Err(err) => ApiError::from(err).into_response(),
+    }
+}
+
+// END: API Keys Admin API
+
+// BEGIN: Log Filters Admin API
+
+/// Base path for our log filters admin API, which changes what's logged while the app is running. Filters are
+/// identified by their log output, e.g. "/api/admin/log-filters/stdout".
+pub const LOG_FILTERS_ADMIN_API: &str = "/api/admin/log-filters";
+
+/// Returns the active filter of each log output
+pub async fn get_log_filters() -> impl IntoResponse {
+    (StatusCode::OK, Json(filters::get_log_filters().get_all())).into_response()
+}
+
+/// Replaces a log output's filter, optionally reverting it to the configured filter after `ttl_seconds`
+pub async fn set_log_filter(
+    Extension(principal): Extension<Principal>,
+    Path(output): Path<String>,
+    Json(update): Json<LogFilterUpdateRequest>,
+) -> impl IntoResponse {
+    match filters::get_log_filters().set(
+        &output,
+        &update.directives,
+        update.ttl_seconds.map(Duration::from_secs),
+    ) {
+        Ok(log_filter) => {
+            // A warning, so the change shows up whatever the new filter is
+            tracing::warn!(
+                subject = ?principal.subject,
+                "Log filter for {output} changed to \"{}\"",
+                log_filter.directives
+            );
+            (StatusCode::OK, Json(log_filter)).into_response()
+        }
+        Err(err) => ApiError::from(err).into_response(),
+    }
+}
+
+/// Reverts a log output to its configured filter
+pub async fn reset_log_filter(
+    Extension(principal): Extension<Principal>,
+    Path(output): Path<String>,
+) -> impl IntoResponse {
+    match filters::get_log_filters().reset(&output) {
+        Ok(log_filter) => {
+            tracing::warn!(
+                subject = ?principal.subject,
+                "Log filter for {output} reset to \"{}\"",
+                log_filter.directives
+            );
+            (StatusCode::OK, Json(log_filter)).into_response()
+        }
+        Err(err) => ApiError::from(err).into_response(),
+    }
+}
+
+// END: Log Filters Admin API
+
+// BEGIN: Auth API
+
+/// Base path for our Auth API, which logs users in and out of the app with single sign-on
+pub const AUTH_API: &str = "/api/auth";
+const LOGIN_PATH: &str = "/login";
+/// Must match the path of the oidc_redirect_url config value
+const LOGIN_CALLBACK_PATH: &str = "/callback";
+const LOGOUT_PATH: &str = "/logout";
+const CURRENT_PRINCIPAL_PATH: &str = "/me";
+
+/// Returns a properly formatted path for one of our Auth API endpoints
+pub fn build_auth_path(path: &str) -> String {
+    format!("{}{}", AUTH_API, path)
+}
+
+/// Starts a single sign-on login by redirecting the browser to the identity provider's login page. The browser gets the
+/// login's state in a cookie, which it must send back to the callback.
+pub async fn login(State(app_state): State<AppState>) -> impl IntoResponse {
+    let Some(config) = app_state.config.oidc.as_ref() else {
+        return ApiError::not_found("Single sign-on is not configured").into_response();
+    };
+
+    match oidc::begin_login(&app_state.db_pool, config).await {
+        Ok(login) => (
+            [(
+                header::SET_COOKIE,
+                sessions::build_login_state_cookie(
+                    &login.state,
+                    oidc::LOGIN_MAX_AGE,
+                    config.redirect_url.starts_with("https"),
+                ),
+            )],
+            Redirect::to(login.authorization_url.as_str()),
+        )
+            .into_response(),
+        Err(err) => ApiError::from(err).into_response(),
+    }
+}
+
+/// Where the identity provider redirects the browser back to after the user logs in. Starts the user's session and
+/// redirects to the root of the SPA. Only the browser that started the login, i.e. has its state cookie, can finish it.
+pub async fn login_callback(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<LoginCallbackParams>,
+) -> impl IntoResponse {
+    let Some(config) = app_state.config.oidc.as_ref() else {
+        return ApiError::not_found("Single sign-on is not configured").into_response();
+    };
+
+    // We're being called at the redirect url, so it tells us whether the browser is using https
+    let secure: bool = config.redirect_url.starts_with("https");
+    // The login is over whether it succeeded or not
+    (
+        AppendHeaders([(
+            header::SET_COOKIE,
+            sessions::build_expired_login_state_cookie(secure),
+        )]),
+        complete_login(&app_state, config, &headers, params, secure).await,
+    )
+        .into_response()
+}
+
+async fn complete_login(
+    app_state: &AppState,
+    config: &OidcConfig,
+    headers: &HeaderMap,
+    params: LoginCallbackParams,
+    secure: bool,
+) -> Response {
+    let (Some(code), Some(state)) = (params.code, params.state) else {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Login failed: {}",
+                params
+                    .error_description
+                    .or(params.error)
+                    .unwrap_or_else(|| String::from("missing code"))
+            ),
+        )
+        .into_response();
+    };
+
+    // Otherwise a callback URL from someone else's login would log this browser in as them
+    if sessions::get_login_state(headers) != Some(state.as_str()) {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Login failed: it was started in another browser, or has expired",
+        )
+        .into_response();
+    }
+
+    let user_id: uuid::Uuid = match oidc::complete_login(
+        &app_state.db_pool,
+        config,
+        &app_state.config.auth_default_user_scopes,
+        &code,
+        &state,
+    )
+    .await
+    {
+        Ok(user_id) => user_id,
+        Err(err) => return ApiError::from(err).into_response(),
+    };
+
+    let max_age: Duration = app_state.config.auth_session_max_age;
+    match sessions::create_session(&app_state.db_pool, user_id, max_age).await {
+        Ok(token) => (
+            [(
+                header::SET_COOKIE,
+                sessions::build_session_cookie(&token, max_age, secure),
+            )],
+            Redirect::to("/"),
+        )
+            .into_response(),
+        Err(err) => ApiError::from(err).into_response(),
+    }
+}
+
+/// Ends the user's session
+pub async fn logout(State(app_state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if let Some(token) = sessions::get_session_token(&headers) {
+        if let Err(err) = sessions::delete_session(&app_state.db_pool, token).await {
+            return ApiError::from(err).into_response();
+        }
+    }
+
+    let secure: bool = app_state
+        .config
+        .oidc
+        .as_ref()
+        .is_some_and(|config| config.redirect_url.starts_with("https"));
+    (
+        StatusCode::NO_CONTENT,
+        [(
+            header::SET_COOKIE,
+            sessions::build_expired_session_cookie(secure),
+        )],
+    )
+        .into_response()
+}
+
+/// Returns who the caller is and the scopes they've been granted
+pub async fn get_current_principal(
+    Extension(principal): Extension<Principal>,
+) -> impl IntoResponse {
+    (StatusCode::OK, Json(principal)).into_response()
+}
+
+// END: Auth API
+
 /// Takes an Axum Response Body, which is assumed to be JSON, and desrializes it back into the JSON-type
 /// the caller expects
 pub async fn deserialize_api_resource<T: serde::de::DeserializeOwned>(
@@ -143,9 +507,10 @@ pub async fn deserialize_api_resource<T: serde::de::DeserializeOwned>(
 mod tests {
 
     use super::*;
-    use crate::DB_MIGRATOR;
+    use crate::{services::configs::AppConfig, DB_MIGRATOR};
     use pretty_assertions::assert_eq;
     use sqlx::PgPool;
+    use std::sync::Arc;
 
     // This needs to align the number of rows inserted in the migrations script whose name contains "seeding_player_data"
     const NUM_SEED_PLAYER_ROWS: usize = 6;
@@ -153,9 +518,11 @@ mod tests {
     const SEED_PLAYER_USER_NAME: &str = "kobe";
 
     fn build_app_state(db_pool: PgPool) -> axum::extract::State<AppState> {
+        let config: AppConfig = AppConfig::load().unwrap();
         axum::extract::State(AppState {
+            search_client: search::get_client(&config).unwrap(),
+            config: Arc::new(config),
             db_pool,
-            search_client: search::get_client().unwrap(),
         })
     }
 
@@ -0,0 +1,94 @@
+//! Defines the error type our API endpoints and middleware return, so every failure is reported to the caller with the
+//! same JSON shape (see [`ErrorResponse`]).
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::services::{auth::oidc::OidcError, request_id, tracing::filters::LogFilterError};
+
+use super::resources::ErrorResponse;
+
+/// An error to return to the API caller, with the HTTP status code to respond with and a human readable message.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// The caller didn't provide valid credentials
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::UNAUTHORIZED, message)
+    }
+
+    /// The caller's credentials are valid, but don't grant access to what was requested
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::FORBIDDEN, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => ApiError::not_found(err.to_string()),
+            _ => ApiError::internal(err.to_string()),
+        }
+    }
+}
+
+impl From<OidcError> for ApiError {
+    fn from(err: OidcError) -> Self {
+        match err {
+            OidcError::Provider(_) => ApiError::new(StatusCode::BAD_GATEWAY, err.to_string()),
+            OidcError::InvalidLogin(_) => ApiError::new(StatusCode::BAD_REQUEST, err.to_string()),
+            OidcError::Database(err) => ApiError::from(err),
+        }
+    }
+}
+
+impl From<LogFilterError> for ApiError {
+    fn from(err: LogFilterError) -> Self {
+        match err {
+            LogFilterError::UnknownOutput(_) => ApiError::not_found(err.to_string()),
+            LogFilterError::InvalidFilter(_) => {
+                ApiError::new(StatusCode::BAD_REQUEST, err.to_string())
+            }
+            LogFilterError::Reload(_) => ApiError::internal(err.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            status: self.status.as_u16(),
+            error: self
+                .status
+                .canonical_reason()
+                .unwrap_or("Unknown")
+                .to_string(),
+            message: self.message,
+            request_id: request_id::get_current_request_id(),
+        };
+
+        (self.status, Json(body)).into_response()
+    }
+}
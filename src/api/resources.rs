@@ -1,8 +1,11 @@
 //! Defines the REST resources that our public APIs provide.
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::{self, Uuid};
 
+use crate::services::auth::Scope;
+
 /// Represents a Player, with a unique ID and username
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Player {
@@ -18,3 +21,88 @@ pub struct Player {
 pub struct SearchRequest {
     pub term: String,
 }
+
+/// Represents an API key as returned by our admin APIs. The key itself is never returned after creation, only its
+/// non-secret prefix so it can be identified.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Represents a request to create a new API key
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiKeyCreateRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// Represents a newly created API key. This is the only time the full key is available, it's up to the caller to store it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreatedApiKey {
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// Represents the query parameters an identity provider redirects a user back to us with after they log in. See
+/// [`services::auth::oidc`](crate::services::auth::oidc).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginCallbackParams {
+    pub state: Option<String>,
+    pub code: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+/// Represents the body of every error response our APIs return
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+    pub status: u16,
+    pub error: String,
+    pub message: String,
+    /// The request's `X-Request-Id`, to quote when reporting the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Represents the body of our readiness probe's response, with the outcome of each dependency's check
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    /// True once the app server has been told to shut down, which makes it not ready regardless of its checks
+    pub shutting_down: bool,
+    pub checks: Vec<DependencyCheck>,
+}
+
+/// Represents the outcome of checking one of our dependencies, e.g. the database
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DependencyCheck {
+    pub name: String,
+    pub healthy: bool,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Represents the filter of one of our log outputs (e.g. stdout), in `RUST_LOG` directive syntax
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogFilter {
+    pub output: String,
+    pub directives: String,
+    /// The configured filter, which the output reverts to once the active one expires
+    pub default_directives: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Represents a request to change the filter of one of our log outputs, optionally for a limited time
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogFilterUpdateRequest {
+    pub directives: String,
+    pub ttl_seconds: Option<u64>,
+}
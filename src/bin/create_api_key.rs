@@ -0,0 +1,47 @@
+//! A utility for creating an API key from the command line, e.g. to bootstrap the first key with the "admin" scope
+//! which can then manage all other keys through the API Keys admin API.
+//!
+//! Usage: cargo create_api_key <name> <comma separated scopes>
+use colored::Colorize;
+use rust_react_app_hello_world::services::{self, auth, configs::AppConfig};
+use sqlx::Postgres;
+
+#[tokio::main]
+async fn main() {
+    let app_config: AppConfig = AppConfig::load().unwrap_or_else(|error| {
+        panic!("Fatal problem loading the config: {error}");
+    });
+
+    // Init tracing/logging
+    let _tracing_guard = services::tracing::init_tracing(&app_config);
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        panic!("Usage: cargo create_api_key <name> <comma separated scopes, e.g. players:read,players:write>");
+    }
+
+    let scopes: Vec<auth::Scope> = auth::parse_scopes(&args[2]).unwrap_or_else(|error| {
+        panic!("Invalid scopes: {error}");
+    });
+
+    let db_pool: sqlx::Pool<Postgres> = services::db::init_db_conn_pool(&app_config)
+        .await
+        .unwrap_or_else(|error| {
+            panic!("Fatal problem initializng the database: {error}");
+        });
+
+    let created = auth::api_keys::create_api_key(&db_pool, &args[1], &scopes)
+        .await
+        .unwrap_or_else(|error| {
+            panic!("Fatal problem creating the API key: {error}");
+        });
+
+    // Printed rather than traced so it's visible regardless of the log level
+    println!(
+        "{} {}\n{}",
+        "Created API key".green(),
+        created.api_key.prefix,
+        created.key.green().bold()
+    );
+    println!("Store it somewhere safe, it can't be shown again.");
+}
@@ -1,20 +1,26 @@
 //! A utility for initalizing repos on developer or test environments.
 use colored::Colorize;
-use rust_react_app_hello_world::{environment_utils::dev_and_test_utils, services};
+use rust_react_app_hello_world::{
+    environment_utils::dev_and_test_utils,
+    services::{self, configs::AppConfig},
+};
 use sqlx::Postgres;
 
 #[tokio::main]
 async fn main() {
+    let app_config: AppConfig = AppConfig::load().unwrap_or_else(|error| {
+        panic!("Fatal problem loading the config: {error}");
+    });
+
     // Init tracing/logging
-    services::tracing::init_tracing();
+    let _tracing_guard = services::tracing::init_tracing(&app_config);
 
     // Init the DB pool
-    let db_pool: sqlx::Pool<Postgres> =
-        services::db::init_db_conn_pool()
-            .await
-            .unwrap_or_else(|error| {
-                panic!("Fatal problem initializng the database: {error}");
-            });
+    let db_pool: sqlx::Pool<Postgres> = services::db::init_db_conn_pool(&app_config)
+        .await
+        .unwrap_or_else(|error| {
+            panic!("Fatal problem initializng the database: {error}");
+        });
 
     // Init the db schema and seed with sample data
     dev_and_test_utils::database_init_and_seed(db_pool.clone())
@@ -24,7 +30,7 @@ async fn main() {
         });
 
     // Init the search service and seed the index with data
-    dev_and_test_utils::search_service_init_and_seed(db_pool.clone())
+    dev_and_test_utils::search_service_init_and_seed(&app_config, db_pool.clone())
         .await
         .unwrap_or_else(|error| {
             panic!("Fatal problem initializng the search service: {error}");
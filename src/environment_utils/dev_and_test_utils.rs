@@ -3,7 +3,7 @@
 //! 1. Creates the database's initial schema and seeds with some sample data
 //! 2. Creates the search index(es) and seeds it with some sample data from the DB.
 //!
-//! Both utilities assume the database and search service are running, available, and configured to what's in .env.
+//! Both utilities assume the database and search service are running, available, and configured to what's in our config.
 //!
 //! As the application grows in scope and schema, you'll need to update this utility accordingly.
 //!
@@ -13,6 +13,8 @@
 //! A production-ized version of this will be needed that only focuses on creating db schema and index creation, along with
 //! whatever legitimate/real data seeding is needed.
 
+use std::sync::Arc;
+
 use axum::response::IntoResponse;
 use colored::Colorize;
 use meilisearch_sdk::{client::*, errors::Error, indexes::Index, task_info::TaskInfo};
@@ -21,7 +23,7 @@ use sqlx::{migrate::MigrateError, PgPool, Postgres};
 use crate::{
     endpoints,
     resources::Player,
-    services::{self, app_server::AppState, search},
+    services::{self, app_server::AppState, configs::AppConfig, search},
     DB_MIGRATOR,
 };
 
@@ -29,9 +31,12 @@ use crate::{
 const PLAYER_NAME_TO_FIND: &str = "kobe";
 
 /// Creates the search index
-pub async fn search_service_init(index_name: &str) -> Result<Client, Error> {
+pub async fn search_service_init(
+    app_config: &AppConfig,
+    index_name: &str,
+) -> Result<Client, Error> {
     // Create a serach client
-    let client = services::search::get_client()?;
+    let client = services::search::get_client(app_config)?;
 
     // Create the index
     let task = client.create_index(index_name, None).await.unwrap();
@@ -45,20 +50,26 @@ pub async fn search_service_init(index_name: &str) -> Result<Client, Error> {
 /// Creates the search indexes and seeds it with some sample data from the DB.
 /// Note: when using search for real, we'll rely on a real system for notifications of data insert or change
 /// from whatever the source system is (DB, file service, etc.)
-pub async fn search_service_init_and_seed(db_pool: PgPool) -> Result<Client, Error> {
-    search_service_init_and_seed_with_idx(db_pool, search::get_player_index_name()).await
+pub async fn search_service_init_and_seed(
+    app_config: &AppConfig,
+    db_pool: PgPool,
+) -> Result<Client, Error> {
+    search_service_init_and_seed_with_idx(app_config, db_pool, &app_config.player_search_index)
+        .await
 }
 
 /// Creates the search indexes and seeds it with some sample data from the DB using the specific index name
 pub async fn search_service_init_and_seed_with_idx(
+    app_config: &AppConfig,
     db_pool: PgPool,
     index_name: &str,
 ) -> Result<Client, Error> {
     // Create a serach client
-    let client = search_service_init(index_name).await.unwrap();
+    let client = search_service_init(app_config, index_name).await.unwrap();
 
     // Insert the Player data into the index
     let insert_task: TaskInfo = seed_player_index(
+        app_config,
         client.clone(),
         &client.get_index(index_name).await.unwrap(),
         db_pool,
@@ -89,11 +100,13 @@ pub async fn search_service_init_and_seed_with_idx(
 
 /// Read in all Players from the DB and insert them in bulk into the Search index
 async fn seed_player_index(
+    app_config: &AppConfig,
     search_client: Client,
     players_idx: &Index,
     db_pool: sqlx::Pool<Postgres>,
 ) -> TaskInfo {
     let resp = endpoints::get_players(axum::extract::State(AppState {
+        config: Arc::new(app_config.clone()),
         db_pool,
         search_client,
     }))
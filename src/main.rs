@@ -1,30 +1,35 @@
 use meilisearch_sdk::client::Client;
 use sqlx::Postgres;
 
-use ::rust_react_app_hello_world::services;
+use ::rust_react_app_hello_world::services::{self, configs::AppConfig};
 
 #[tokio::main]
 async fn main() {
+    // Load and validate the config, before anything else so every problem with it is reported up front
+    let app_config: AppConfig = AppConfig::load().unwrap_or_else(|error| {
+        panic!("Fatal problem loading the config: {error}");
+    });
+
     // Init tracing/logging
-    services::tracing::init_tracing();
+    // Held until main returns, so spans are flushed to the collector on shutdown
+    let _tracing_guard = services::tracing::init_tracing(&app_config);
 
     // Init the DB
     // For now passing the connpool around, and specifically using as shared State in our app server below, which seems
     // to be the common pattern. Considered/considering using as a global shared constant with OnceCell as the backing impl.
-    let db_pool: sqlx::Pool<Postgres> =
-        services::db::init_db_conn_pool()
-            .await
-            .unwrap_or_else(|error| {
-                panic!("Fatal problem initializng the database: {error}");
-            });
+    let db_pool: sqlx::Pool<Postgres> = services::db::init_db_conn_pool(&app_config)
+        .await
+        .unwrap_or_else(|error| {
+            panic!("Fatal problem initializng the database: {error}");
+        });
 
     // Init the Search server
-    let search_client: Client = services::search::get_client().unwrap_or_else(|error| {
+    let search_client: Client = services::search::get_client(&app_config).unwrap_or_else(|error| {
         panic!("Fatal problem initializing the serach client: {error}");
     });
 
     // Init the app server
-    services::app_server::init_app_server(db_pool, search_client)
+    services::app_server::init_app_server(app_config, db_pool, search_client)
         .await
         .unwrap_or_else(|error| {
             panic!("Fatal problem initializng the app server: {error}");
@@ -1,6 +1,16 @@
 //! Provides utilities for interacting with the various services that serve our applicaiton
 pub mod app_server;
+pub mod auth;
 pub mod configs;
+pub mod cors;
 pub mod db;
+pub mod health;
+pub mod metrics;
+pub mod rate_limit;
+pub mod request_id;
 pub mod search;
+pub mod security_headers;
+pub mod spa;
+pub mod static_mounts;
+pub mod timeouts;
 pub mod tracing;
@@ -1,56 +1,178 @@
 //! Provides utilities to initialize usage of the App Server and provide functions to interact with it.
 //
+pub mod listener;
+pub mod serve;
+pub mod systemd;
+pub mod tls;
+
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::api::endpoints;
-use crate::services::configs;
-use axum::http::StatusCode;
-use axum::routing::{post, put};
-use axum::{response, routing::get, Router};
+use crate::api::{endpoints, errors::ApiError};
+use crate::services::{
+    auth,
+    configs::AppConfig,
+    health, metrics,
+    rate_limit::{self, RateLimiter},
+    request_id, security_headers, spa, static_mounts,
+    timeouts::{self, RequestTimeouts},
+};
+use axum::http::{Method, StatusCode};
+use axum::{middleware, routing::any, Router};
 use colored::Colorize;
+use listener::Listener;
 use meilisearch_sdk::client::Client;
+use serve::ConnectionConfig;
 use sqlx::Postgres;
 use tokio::net::TcpListener;
 use tokio::signal::unix::SignalKind;
 use tokio::signal::{self, unix};
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
 use tower_http::{
-    compression::CompressionLayer,
-    decompression::RequestDecompressionLayer,
-    services::{ServeDir, ServeFile},
-    timeout::TimeoutLayer,
-    trace::TraceLayer,
+    compression::CompressionLayer, decompression::RequestDecompressionLayer, trace::TraceLayer,
 };
 
+/// Base path of our APIs: unmatched requests under it get a JSON 404, and static mounts can't shadow it
+pub const API_BASE_PATH: &str = "/api";
+
 /// Initalize an Axum app server with the following features:
 ///
-/// 1. Routes defined to serve the Single Page Application (SPA) static files as well as API endpoints
-/// 2. Response compression
-/// 3. Graceful shutdown (waits up to APP_SERVER_GRACEFUL_SHUTDOWN_MAX_DURATION seconds for in-flight requests to finish)
-/// 4. Basic request and response logging
+/// 1. Routes defined to serve the Single Page Application (SPA) static files as well as API endpoints, with the SPA
+///    optionally embedded in the binary (the `embed-spa` cargo feature), or proxied to its dev server in dev mode, and
+///    any files and directories mounted in STATIC_MOUNTS (see [`static_mounts`])
+/// 2. Response compression, with the SPA's files served precompressed and its content-hashed files cached for good
+///    (see [`spa`])
+/// 3. Graceful shutdown (reports not ready for APP_SERVER_SHUTDOWN_DRAIN_DELAY seconds so load balancers stop sending
+///    traffic, then waits up to APP_SERVER_GRACEFUL_SHUTDOWN_MAX_DURATION seconds for in-flight requests to finish,
+///    answering any still running with a 503 and closing their connections)
+/// 4. Basic request and response logging, correlated by each request's X-Request-Id, and request timeouts per group of
+///    routes (see [`timeouts`])
+/// 5. Authentication and per-client rate limiting of API endpoints
+/// 6. Security headers on every response, including a Content-Security-Policy with per-response nonces for HTML
+/// 7. An optional CORS policy for API endpoints, for when the SPA is hosted on another origin
+/// 8. Prometheus metrics at /metrics
+/// 9. Optional TLS with certificate hot reload, plus a plain HTTP listener that redirects to HTTPS (see [`tls`])
+/// 10. HTTP/1 and HTTP/2 (negotiated over TLS, or h2c with prior knowledge), with connection limits (see [`serve`])
+/// 11. Listening on TCP, a Unix domain socket, and sockets from systemd socket activation (see [`listener`]), and
+///     notifying systemd when we're ready and when we start shutting down (see [`systemd`])
+/// 12. An optional admin listener at ADMIN_SERVER_URL for health probes, metrics and admin APIs, so they aren't served
+///     on the public port (see [`init_admin_router`])
 ///
 pub async fn init_app_server(
+    config: AppConfig,
     db_pool: sqlx::Pool<Postgres>,
     search_client: Client,
 ) -> Result<(), std::io::Error> {
-    let app: axum::Router = init_router(db_pool, search_client);
+    let config: Arc<AppConfig> = Arc::new(config);
+    let shutdown: Shutdown = Shutdown::spawn(config.app_server_shutdown_drain_delay);
+
+    let app_state: AppState = AppState {
+        config: Arc::clone(&config),
+        db_pool,
+        search_client,
+    };
+
+    // Serve operational routes on their own listener when configured, so they needn't be exposed publicly
+    let admin_server = match &config.admin_server_url {
+        Some(admin_server_url) => {
+            let admin_listener = TcpListener::bind(admin_server_url).await?;
+            tracing::debug!(
+                "{} {}",
+                "Admin server listening on".green().bold(),
+                admin_listener.local_addr().unwrap().to_string().green()
+            );
+            Some(serve::serve(
+                vec![admin_listener.into()],
+                None,
+                init_admin_router(app_state.clone()),
+                config.app_server_connections.clone(),
+                shutdown.clone().wait(),
+            ))
+        }
+        None => None,
+    };
+
+    let listeners: Vec<Listener> = Listener::bind_all(&config).await?;
+    let tls_acceptor: Option<TlsAcceptor> = match &config.tls {
+        Some(tls_config) => Some(tls::init_tls(tls_config).map_err(std::io::Error::other)?),
+        None => None,
+    };
+
+    // Redirect plain HTTP to HTTPS when configured
+    if let Some(redirect_server_url) = config
+        .tls
+        .as_ref()
+        .and_then(|tls_config| tls_config.redirect_server_url.as_ref())
+    {
+        // Without a TCP socket, HTTPS is served on its default port by whatever proxies to our Unix domain socket
+        let https_port: Option<u16> = listeners.iter().find_map(Listener::port);
+        let redirect_app: axum::Router =
+            tls::redirect_routes(https_port.unwrap_or(tls::DEFAULT_HTTPS_PORT));
+        let redirect_listener = TcpListener::bind(redirect_server_url).await?;
+
+        tracing::debug!(
+            "{} {}",
+            "HTTPS redirect server listening on".green().bold(),
+            redirect_listener.local_addr().unwrap().to_string().green()
+        );
+
+        let redirect_shutdown: Shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(redirect_listener, redirect_app)
+                .with_graceful_shutdown(redirect_shutdown.wait())
+                .await
+            {
+                tracing::error!("{} {:?}", "HTTPS redirect server error".red(), err);
+            }
+        });
+    }
 
-    let listener = TcpListener::bind(configs::get_env_var_or_panic("app_server_url")).await?;
+    rate_limit::init_bucket_pruning(&config.rate_limit, &app_state.db_pool);
 
-    tracing::debug!(
-        "{} {}",
-        "App server listening on".green().bold(),
-        listener
-            .local_addr()
-            .unwrap()
-            .to_string()
+    // An embedded SPA is compressed per request
+    #[cfg(not(feature = "embed-spa"))]
+    if config.spa_precompress_files {
+        spa::init_precompressed_files(&config);
+    }
+
+    let connection_config: ConnectionConfig = config.app_server_connections.clone();
+    let app: axum::Router = init_router(app_state);
+
+    for listener in &listeners {
+        tracing::debug!(
+            "{} {}{}",
+            "App server listening on".green().bold(),
+            match (listener, tls_acceptor.is_some()) {
+                (Listener::Unix(..), _) => "unix:",
+                (Listener::Tcp(_), true) => "https://",
+                (Listener::Tcp(_), false) => "http://",
+            }
             .green()
             .bold()
-            .underline()
+            .underline(),
+            listener.to_string().green().bold().underline()
+        );
+    }
+
+    // Our own serve loop (rather than axum::serve) so connections can be wrapped in TLS and limited. It also gives the
+    // rate limiter the client's IP address for anonymous requests.
+    let app_server = serve::serve(
+        listeners,
+        tls_acceptor,
+        app,
+        connection_config,
+        shutdown.wait(),
     );
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    systemd::notify_ready();
+    // Both listeners stop accepting on the same signal, and we wait for both to finish their in-flight requests
+    match admin_server {
+        Some(admin_server) => {
+            tokio::join!(app_server, admin_server);
+        }
+        None => app_server.await,
+    }
 
     Ok(())
 }
@@ -58,13 +180,16 @@ pub async fn init_app_server(
 /// Contains all shared state the Router will provide the handler of each request
 #[derive(Clone)]
 pub struct AppState {
+    pub config: Arc<AppConfig>,
     pub db_pool: sqlx::Pool<Postgres>,
     pub search_client: Client,
 }
 
 /// Initializes a [`axum::routing::Router`] with endpoint routes and other server runtime features
 /// TODO SWY: This is only public to make it accessible for integrationt tests that need to boot up the app server with axum-test's approach
-pub fn init_router(db_pool: sqlx::Pool<Postgres>, search_client: Client) -> Router {
+pub fn init_router(app_state: AppState) -> Router {
+    let config: Arc<AppConfig> = Arc::clone(&app_state.config);
+
     // Implement response compression
     let compression_layer: CompressionLayer = CompressionLayer::new()
         .br(true)
@@ -72,59 +197,92 @@ pub fn init_router(db_pool: sqlx::Pool<Postgres>, search_client: Client) -> Rout
         .gzip(true)
         .zstd(true);
 
-    let app_state: AppState = AppState {
-        db_pool,
-        search_client,
-    };
+    let rate_limiter: RateLimiter =
+        RateLimiter::from_config(&config.rate_limit, &app_state.db_pool);
+    metrics::init_metrics();
 
-    axum::Router::new()
+    // Operational routes are only served here when there's no admin listener for them
+    let serve_admin_routes: bool = config.admin_server_url.is_none();
+    let timeouts: &RequestTimeouts = &config.request_timeouts;
+    let mut endpoint_routes: Router<AppState> = endpoints::routes()
+        .route_layer(middleware::from_fn_with_state(
+            timeouts.api,
+            timeouts::limit,
+        ))
+        .merge(
+            endpoints::search_routes().route_layer(middleware::from_fn_with_state(
+                timeouts.search,
+                timeouts::limit,
+            )),
+        );
+    if serve_admin_routes {
+        endpoint_routes = endpoint_routes.merge(endpoints::admin_routes().route_layer(
+            middleware::from_fn_with_state(timeouts.api, timeouts::limit),
+        ));
+    }
+
+    // Route layers run bottom up, so callers are authenticated before they're rate limited
+    let mut api_routes: Router<AppState> = endpoint_routes
+        .route_layer(middleware::from_fn_with_state(
+            rate_limiter,
+            rate_limit::limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::authenticate,
+        ));
+    // Unmatched API requests get JSON errors like the rest of our APIs, rather than falling through to the SPA. They
+    // skip authentication and rate limiting, since there's nothing to guard.
+    api_routes = api_routes
+        .method_not_allowed_fallback(handler_405)
+        .route(API_BASE_PATH, any(handler_404))
+        .route(&format!("{API_BASE_PATH}/*path"), any(handler_404));
+    // CORS goes outside of authentication, since browsers send preflight requests without credentials. It's only
+    // added to the API routes so the SPA's static files stay same-origin.
+    if let Some(cors_config) = config.cors.clone() {
+        api_routes = api_routes.layer(cors_config.into_layer());
+    }
+
+    // Health probes and metrics, unless they're served on the admin listener
+    let mut operations_routes: Router<AppState> = Router::new();
+    if serve_admin_routes {
+        operations_routes = operations_routes
+            .merge(health::routes())
+            .merge(metrics::routes());
+    }
+
+    let static_routes: Router<AppState> = Router::new()
         // Route for serving our Single Page Application (SPA)
         // Note tha fallback file is the SPA's root index.html, so that this server knows to send all url requests
-        // (excpet where overridden later) to the SPA boostrap file which then handles everything from there.
-        .nest_service(
-            "/",
-            ServeDir::new(
-                // The SPA's build/distribution directory where all the compiled, static files reside
-                configs::get_env_var_or_panic("spa_dist_dir"),
-            )
-            .not_found_service(ServeFile::new(
-                // The url for the core SPA bootstraping file
-                configs::get_env_var_or_panic("spa_fallback_url"),
-            )),
-        )
-        //
-        //*** BEGIN: Add in all endpoints from our public APIs
-        //
-        // TODO SWY: We need to find a way to separate concerns with this section and have the endpoints returned
-        // by ::api::endpoints and then built into routes in this function.
-        .route(endpoints::PLAYERS_API, get(endpoints::get_players))
-        .route(
-            endpoints::build_id_path(endpoints::PLAYERS_API).as_str(),
-            get(endpoints::get_player),
-        )
-        .route(endpoints::PLAYERS_API, put(endpoints::add_player))
-        .route(
-            endpoints::build_player_search_path().as_str(),
-            post(endpoints::search_players),
-        )
-        //
-        // *** END: Add in all endpoints from our public APIs
-        //
-        // Example of a routing an URL to a random static html file (something outside the SPA)
-        .nest_service("/other-page", ServeFile::new("sample_page.html"))
+        // (excpet where overridden later) to the SPA boostrap file which then handles everything from there. It's
+        // rendered with our runtime config, see spa::index.
+        .merge(spa::routes(&config))
+        // Files and directories outside the SPA (e.g. our sample page at /other-page), see static_mounts
+        .merge(static_mounts::routes(&config.static_mounts))
+        // Liveness and readiness probes for our orchestrator and load balancers, and metrics
+        .merge(operations_routes)
+        .layer(middleware::from_fn_with_state(
+            timeouts.default,
+            timeouts::limit,
+        ));
+
+    axum::Router::new()
+        .merge(static_routes)
+        // Add in all endpoints from our public APIs, with their own timeouts
+        .merge(api_routes)
+        // Gives every request the nonce the SPA's index.html is rendered with, and every response our security headers
+        .layer(middleware::from_fn_with_state(
+            config.security_headers.clone(),
+            security_headers::set_security_headers,
+        ))
         .layer(RequestDecompressionLayer::new())
         .layer(compression_layer)
-        .layer((
-            // Where request/response tracing/logging is declared
-            TraceLayer::new_for_http(),
-            // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so
-            // requests don't hang forever.
-            TimeoutLayer::new(Duration::from_secs(u64::from(
-                configs::get_env_var_as_number_or_panic(
-                    "app_server_graceful_shutdown_max_duration",
-                ),
-            ))),
-        ))
+        // Where request/response tracing/logging is declared, with a span per request holding its ID
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::make_request_span))
+        // Outside of the timeouts, so requests that time out are counted too
+        .layer(middleware::from_fn(metrics::track_requests))
+        // Outermost, so the request ID is assigned before anything else (including tracing) sees the request
+        .layer(middleware::from_fn(request_id::propagate))
         // We add in the AppState which makes the DB conn pool and Search client (and future things) available to the method
         // handlers for our enddpoints added above.
         // see: https://mo8it.com/blog/sqlx-integration-in-axum/#states and https://docs.rs/axum/latest/axum/extract/struct.State.html
@@ -132,16 +290,68 @@ pub fn init_router(db_pool: sqlx::Pool<Postgres>, search_client: Client) -> Rout
         .fallback(handler_404)
 }
 
-async fn handler_404() -> impl response::IntoResponse {
-    (
-        StatusCode::NOT_FOUND,
-        "Ivalid or malformed URL, please check and try again or report the issue.",
+/// Initializes the [`axum::routing::Router`] for the admin listener: health probes, metrics and our admin APIs. It's
+/// only reachable internally, so it skips what's there for browsers and public clients (e.g. the SPA, CORS and rate
+/// limiting), but admin APIs still require credentials.
+pub fn init_admin_router(app_state: AppState) -> Router {
+    metrics::init_metrics();
+
+    let admin_api_routes: Router<AppState> = endpoints::admin_routes().route_layer(
+        middleware::from_fn_with_state(app_state.clone(), auth::authenticate),
+    );
+
+    axum::Router::new()
+        .merge(health::routes())
+        .merge(metrics::routes())
+        .merge(admin_api_routes)
+        .route_layer(middleware::from_fn_with_state(
+            app_state.config.request_timeouts.default,
+            timeouts::limit,
+        ))
+        .method_not_allowed_fallback(handler_405)
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::make_request_span))
+        .layer(middleware::from_fn(request_id::propagate))
+        .with_state(app_state)
+        .fallback(handler_404)
+}
+
+async fn handler_404() -> ApiError {
+    ApiError::not_found("Invalid or malformed URL, please check and try again or report the issue.")
+}
+
+/// For requests with a method the URL doesn't support. The Allow header listing the ones it does is added by axum.
+async fn handler_405(method: Method) -> ApiError {
+    ApiError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        format!("{method} isn't supported by this URL"),
     )
 }
 
+/// Completes once we've been told to shut down and traffic has drained. The signal is only listened for and drained
+/// once, however many clones wait for it, so every listener stops accepting together.
+#[derive(Clone)]
+struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Listens for the shutdown signal in the background
+    fn spawn(drain_delay: Duration) -> Self {
+        let (drained, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            shutdown_signal(drain_delay).await;
+            let _ = drained.send(true);
+        });
+        Shutdown(receiver)
+    }
+
+    async fn wait(mut self) {
+        // An error means the background task is gone, which only happens if it panicked, so shut down too
+        let _ = self.0.wait_for(|drained| *drained).await;
+    }
+}
+
 // Bind various ways to detect and listen for a shutdown command, which allows the graceful shutdown above.
 // see: https://github.com/tokio-rs/axum/blob/main/examples/graceful-shutdown/src/main.rs
-async fn shutdown_signal() {
+async fn shutdown_signal(drain_delay: Duration) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -163,4 +373,15 @@ async fn shutdown_signal() {
         _ = ctrl_c => {},
         _ = terminate => {},
     }
+
+    systemd::notify_stopping();
+
+    // Keep serving while load balancers notice we're no longer ready and drain traffic away
+    health::mark_shutting_down();
+    tracing::info!(
+        "{} {:?}",
+        "Shutting down, draining traffic for".yellow(),
+        drain_delay
+    );
+    tokio::time::sleep(drain_delay).await;
 }
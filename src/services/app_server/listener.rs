@@ -0,0 +1,278 @@
+//! Provides the sockets the app server listens on, all served at once:
+//!
+//! - Sockets passed by systemd with socket activation (see [`systemd`](super::systemd))
+//! - A Unix domain socket at `app_server_unix_socket_path` when set, e.g. for a proxy on the same host like nginx
+//! - A TCP socket at `app_server_url`, unless systemd passed a TCP socket, which is then bound to it in place of us
+//!
+//! Requests over Unix domain sockets have no client address. Their client's IP address is taken from the header set
+//! by the proxy in front of them when `app_server_unix_client_ip_header` is set (see
+//! [`ConnectionConfig`](super::serve::ConnectionConfig)), otherwise anonymous ones aren't rate limited by IP address.
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    task::{Context, Poll},
+};
+
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::services::configs::{AppConfig, ConfigReader};
+
+use super::systemd::{self, InheritedSocket};
+
+/// Where and how to create a Unix domain socket, from the app_server_unix_socket_* config values
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    /// Permissions of the socket file, e.g. 0o660 lets the group (e.g. nginx's) connect
+    pub mode: u32,
+}
+
+impl UnixSocketConfig {
+    /// Reads the app_server_unix_socket_* config values. Returns None if they're turned off, i.e.
+    /// `app_server_unix_socket_path` is empty.
+    pub fn from_config(reader: &mut ConfigReader) -> Option<Self> {
+        let path: String = reader.get_optional_string("app_server_unix_socket_path")?;
+        Some(UnixSocketConfig {
+            path: PathBuf::from(path),
+            mode: reader.get_with("app_server_unix_socket_mode", parse_mode),
+        })
+    }
+}
+
+/// Parses file permissions in octal, e.g. "660"
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(String::from("must be octal file permissions, e.g. \"660\"")),
+    }
+}
+
+/// A socket the app server accepts connections on
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The path is set for sockets we created, to remove the file when the listener is dropped. Sockets from systemd are
+    /// left for systemd to clean up.
+    Unix(UnixListener, Option<UnixSocketFile>),
+}
+
+/// A connection accepted by a [`Listener`]
+pub enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    /// Binds the sockets the config and environment call for. Needs to be called within a Tokio runtime.
+    pub async fn bind_all(config: &AppConfig) -> io::Result<Vec<Self>> {
+        let mut listeners: Vec<Listener> = Vec::new();
+        for socket in systemd::take_sockets()? {
+            listeners.push(match socket {
+                InheritedSocket::Tcp(listener) => {
+                    listener.set_nonblocking(true)?;
+                    Listener::Tcp(TcpListener::from_std(listener)?)
+                }
+                InheritedSocket::Unix(listener) => {
+                    listener.set_nonblocking(true)?;
+                    Listener::Unix(UnixListener::from_std(listener)?, None)
+                }
+            });
+        }
+
+        if let Some(unix_socket_config) = &config.app_server_unix_socket {
+            listeners.push(Listener::bind_unix(unix_socket_config)?);
+        }
+        // A TCP socket from systemd is normally bound to app_server_url already, so binding it again would fail
+        if !listeners
+            .iter()
+            .any(|listener| matches!(listener, Listener::Tcp(_)))
+        {
+            listeners.push(Listener::Tcp(
+                TcpListener::bind(&config.app_server_url).await?,
+            ));
+        }
+        Ok(listeners)
+    }
+
+    /// Creates a Unix domain socket, replacing the file left behind if we weren't shut down cleanly
+    pub fn bind_unix(config: &UnixSocketConfig) -> io::Result<Self> {
+        match fs::remove_file(&config.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+
+        let listener: UnixListener = UnixListener::bind(&config.path)?;
+        let socket_file = UnixSocketFile(config.path.clone());
+        fs::set_permissions(&config.path, fs::Permissions::from_mode(config.mode))?;
+        Ok(Listener::Unix(listener, Some(socket_file)))
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<Connection>> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, remote_addr)| Connection::Tcp(stream, remote_addr)),
+            Listener::Unix(listener, _) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+
+    /// The TCP port we're listening on, None for Unix domain sockets
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+            Listener::Unix(..) => None,
+        }
+    }
+}
+
+/// Accepts a connection on whichever of the listeners has one first. They're checked starting from the one at `start`,
+/// which callers vary so a busy listener can't starve the others.
+pub async fn accept_any(listeners: &[Listener], start: usize) -> io::Result<Connection> {
+    std::future::poll_fn(|cx| {
+        for offset in 0..listeners.len() {
+            let listener: &Listener = &listeners[(start + offset) % listeners.len()];
+            if let Poll::Ready(result) = listener.poll_accept(cx) {
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => f.write_str("TCP socket"),
+            },
+            Listener::Unix(listener, _) => match listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(Path::to_path_buf))
+            {
+                Some(path) => write!(f, "{}", path.display()),
+                None => f.write_str("Unix domain socket"),
+            },
+        }
+    }
+}
+
+/// Removes the socket file when dropped, so it isn't left behind after shutting down
+pub struct UnixSocketFile(PathBuf);
+
+impl Drop for UnixSocketFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.0) {
+            tracing::warn!("Failed to remove {}: {err}", self.0.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::services::app_server::serve;
+    use axum::{routing::get, Router};
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn listener_parse_mode() {
+        assert_eq!(parse_mode("660").unwrap(), 0o660);
+        assert_eq!(parse_mode("0777").unwrap(), 0o777);
+        assert!(parse_mode("1777").is_err());
+        assert!(parse_mode("rw-rw----").is_err());
+    }
+
+    /// Validates requests are served over a Unix domain socket, and its file is cleaned up after
+    #[tokio::test]
+    async fn listener_serve_unix_socket() {
+        let config = UnixSocketConfig {
+            path: std::env::temp_dir().join(format!("app_{}.sock", uuid::Uuid::new_v4())),
+            mode: 0o660,
+        };
+        let listener: Listener = Listener::bind_unix(&config).unwrap();
+        assert_eq!(listener.port(), None);
+        assert_eq!(
+            fs::metadata(&config.path).unwrap().permissions().mode() & 0o777,
+            0o660
+        );
+
+        let (shutdown, shutdown_signal) = tokio::sync::oneshot::channel::<()>();
+        let router: Router = Router::new().route("/test", get(|| async { "ok" }));
+        let server = tokio::spawn(serve::serve(
+            vec![listener],
+            None,
+            router,
+            AppConfig::load().unwrap().app_server_connections,
+            async {
+                let _ = shutdown_signal.await;
+            },
+        ));
+
+        let mut stream: UnixStream = UnixStream::connect(&config.path).await.unwrap();
+        stream
+            .write_all(b"GET /test HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("ok"), "{response}");
+
+        shutdown.send(()).unwrap();
+        server.await.unwrap();
+        assert!(!config.path.exists());
+    }
+
+    /// Validates the Unix domain socket is served alongside app_server_url
+    #[tokio::test]
+    async fn listener_bind_all() {
+        let unix_socket_config = UnixSocketConfig {
+            path: std::env::temp_dir().join(format!("app_{}.sock", uuid::Uuid::new_v4())),
+            mode: 0o660,
+        };
+        let mut config: AppConfig = AppConfig::load().unwrap();
+        config.app_server_url = String::from("127.0.0.1:0");
+        config.app_server_unix_socket = Some(unix_socket_config.clone());
+
+        let listeners: Vec<Listener> = Listener::bind_all(&config).await.unwrap();
+        assert_eq!(listeners.len(), 2);
+        let port: u16 = listeners.iter().find_map(Listener::port).unwrap();
+
+        let router: Router = Router::new().route("/test", get(|| async { "ok" }));
+        tokio::spawn(serve::serve(
+            listeners,
+            None,
+            router,
+            config.app_server_connections,
+            std::future::pending(),
+        ));
+
+        let response = reqwest::get(format!("http://127.0.0.1:{port}/test"))
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        let mut stream: UnixStream = UnixStream::connect(&unix_socket_config.path).await.unwrap();
+        stream
+            .write_all(b"GET /test HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("ok"), "{response}");
+    }
+}
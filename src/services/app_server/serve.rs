@@ -0,0 +1,463 @@
+//! Serves our router over connections we accept ourselves rather than with `axum::serve`, so they can be wrapped in
+//! TLS (see [`tls`](super::tls)) before any HTTP is spoken, and so we control how many there are and how long they're
+//! kept open.
+//!
+//! Both HTTP/1 and HTTP/2 are served. Over TLS the protocol is negotiated with ALPN, and over plain TCP HTTP/2 is
+//! served to clients with prior knowledge (h2c), e.g. internal hops from a proxy that already terminated TLS.
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, HeaderName},
+    Router,
+};
+use hyper::{body::Incoming, service::service_fn, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{
+        conn::auto,
+        graceful::{GracefulShutdown, Watcher},
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+use crate::services::configs::ConfigReader;
+
+use super::listener::{self, Connection, Listener};
+
+/// How long a client has to complete the TLS handshake before its connection is dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long connections are kept open after the shutdown deadline, so the 503s sent to their in-flight requests can be
+/// written
+const FORCED_CLOSE_DELAY: Duration = Duration::from_secs(1);
+
+/// How long to wait before accepting again after an error, e.g. running out of file descriptors, which passes once
+/// other connections close
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// How connections to the app server are handled, from the app_server_* config values
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionConfig {
+    /// Most connections open at once. Further clients wait to be accepted until one closes. None means no limit.
+    pub max_connections: Option<usize>,
+    /// How long HTTP/1 clients have to send a request's headers, which includes the wait for the next request on a
+    /// kept-alive connection
+    pub header_read_timeout: Duration,
+    /// Whether HTTP/1 connections are kept open for further requests
+    pub keep_alive: bool,
+    /// How often idle HTTP/2 connections are pinged, closing them if a ping isn't answered within the same time. None
+    /// turns pings off.
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// How long in-flight requests have to finish once we stop accepting connections, after which they're answered with
+    /// a 503 and their connections closed
+    pub graceful_shutdown_timeout: Duration,
+    /// Header the proxy in front of a Unix domain socket sets to its client's IP address, e.g. X-Forwarded-For. It's
+    /// only trusted on requests over Unix domain sockets, whose clients are otherwise unknown.
+    pub unix_client_ip_header: Option<HeaderName>,
+}
+
+impl ConnectionConfig {
+    pub fn from_config(reader: &mut ConfigReader) -> Self {
+        let max_connections: usize = reader.get("app_server_max_connections");
+        let http2_keep_alive_interval: Duration =
+            reader.get_seconds("app_server_http2_keep_alive_interval");
+        let unix_client_ip_header: Option<HeaderName> = reader
+            .get_optional_string("app_server_unix_client_ip_header")
+            .and_then(|name| match HeaderName::from_str(name.trim()) {
+                Ok(header) => Some(header),
+                Err(_) => {
+                    reader.add_problem("app_server_unix_client_ip_header", "must be a header name");
+                    None
+                }
+            });
+
+        ConnectionConfig {
+            max_connections: (max_connections > 0).then_some(max_connections),
+            header_read_timeout: reader.get_seconds("app_server_header_read_timeout"),
+            keep_alive: reader.get("app_server_keep_alive"),
+            http2_keep_alive_interval: (!http2_keep_alive_interval.is_zero())
+                .then_some(http2_keep_alive_interval),
+            graceful_shutdown_timeout: reader
+                .get_seconds("app_server_graceful_shutdown_max_duration"),
+            unix_client_ip_header,
+        }
+    }
+
+    fn build_connection_builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder: auto::Builder<TokioExecutor> = auto::Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(self.header_read_timeout)
+            .keep_alive(self.keep_alive);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .keep_alive_interval(self.http2_keep_alive_interval);
+        if let Some(http2_keep_alive_interval) = self.http2_keep_alive_interval {
+            builder
+                .http2()
+                .keep_alive_timeout(http2_keep_alive_interval);
+        }
+        builder
+    }
+}
+
+/// Serves the router on the listeners until the shutdown signal completes, then waits up to the graceful shutdown
+/// timeout for in-flight requests to finish before closing their connections.
+/// Connections are wrapped in TLS when there's an acceptor. Handlers get the client's address as [`ConnectInfo`], except
+/// for clients connected over a Unix domain socket, and its IP address as [`ClientIp`] whenever it's known.
+pub async fn serve(
+    listeners: Vec<Listener>,
+    tls_acceptor: Option<TlsAcceptor>,
+    router: Router,
+    connection_config: ConnectionConfig,
+    shutdown_signal: impl Future<Output = ()>,
+) {
+    let builder: auto::Builder<TokioExecutor> = connection_config.build_connection_builder();
+    let connection_limit: Option<Arc<Semaphore>> = connection_config
+        .max_connections
+        .map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+    let graceful: GracefulShutdown = GracefulShutdown::new();
+    let (deadline_reached, deadline_receiver) = watch::channel(false);
+    let shutdown_deadline = ShutdownDeadline(deadline_receiver);
+    tokio::pin!(shutdown_signal);
+
+    for accepted_count in 0.. {
+        // At the limit, waits for a connection to close. Until then new clients wait in the listen backlog.
+        let permit: Option<OwnedSemaphorePermit> = match &connection_limit {
+            Some(connection_limit) => tokio::select! {
+                permit = Arc::clone(connection_limit).acquire_owned() => {
+                    Some(permit.expect("The connection limit's semaphore is never closed"))
+                }
+                _ = &mut shutdown_signal => break,
+            },
+            None => None,
+        };
+
+        let connection: Connection = tokio::select! {
+            accepted = listener::accept_any(&listeners, accepted_count) => match accepted {
+                Ok(connection) => connection,
+                Err(err) => {
+                    tracing::error!("Failed to accept a connection: {err}");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown_signal => break,
+        };
+
+        let handler = ConnectionHandler {
+            tls_acceptor: tls_acceptor.clone(),
+            builder: builder.clone(),
+            router: router.clone(),
+            watcher: graceful.watcher(),
+            shutdown_deadline: shutdown_deadline.clone(),
+            unix_client_ip_header: connection_config.unix_client_ip_header.clone(),
+        };
+        tokio::spawn(async move {
+            match connection {
+                Connection::Tcp(stream, remote_addr) => {
+                    handler.handle(stream, Some(remote_addr)).await
+                }
+                Connection::Unix(stream) => handler.handle(stream, None).await,
+            }
+            drop(permit);
+        });
+    }
+
+    // Stop accepting, then let in-flight requests finish
+    drop(listeners);
+    let graceful_shutdown = graceful.shutdown();
+    tokio::pin!(graceful_shutdown);
+    if tokio::time::timeout(
+        connection_config.graceful_shutdown_timeout,
+        &mut graceful_shutdown,
+    )
+    .await
+    .is_err()
+    {
+        tracing::warn!(
+            "Requests still in flight after {:?}, answering them with 503 and closing their connections",
+            connection_config.graceful_shutdown_timeout
+        );
+        let _ = deadline_reached.send(true);
+        graceful_shutdown.await;
+    }
+}
+
+/// The IP address of the client that made the request, when it's known. Added to the request's extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// Reads the address from the header a proxy sets. For a list like X-Forwarded-For's, it's the last one, which the
+    /// proxy added itself rather than passing on from its client.
+    fn from_header(headers: &HeaderMap, header: &HeaderName) -> Option<Self> {
+        let value: &str = headers.get_all(header).iter().next_back()?.to_str().ok()?;
+        let ip: IpAddr = value.rsplit(',').next()?.trim().parse().ok()?;
+        Some(ClientIp(ip))
+    }
+}
+
+/// Lets requests know the shutdown deadline has passed, so they're answered rather than cut off when their connection
+/// is closed. Added to every request's extensions.
+#[derive(Clone)]
+pub struct ShutdownDeadline(watch::Receiver<bool>);
+
+impl ShutdownDeadline {
+    /// Completes once the deadline has passed. Never completes if the server stops before then.
+    pub async fn reached(mut self) {
+        if self.0.wait_for(|reached| *reached).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Everything needed to serve an accepted connection
+struct ConnectionHandler {
+    tls_acceptor: Option<TlsAcceptor>,
+    builder: auto::Builder<TokioExecutor>,
+    router: Router,
+    watcher: Watcher,
+    shutdown_deadline: ShutdownDeadline,
+    unix_client_ip_header: Option<HeaderName>,
+}
+
+impl ConnectionHandler {
+    /// Wraps the connection in TLS when there's an acceptor, then serves it
+    async fn handle<I>(mut self, stream: I, remote_addr: Option<SocketAddr>)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let Some(tls_acceptor) = self.tls_acceptor.take() else {
+            return self.serve(stream, remote_addr).await;
+        };
+
+        // Done on the connection's own task, so a slow client doesn't hold up accepting others
+        let client: String = describe_client(remote_addr);
+        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => self.serve(stream, remote_addr).await,
+            Ok(Err(err)) => tracing::debug!("TLS handshake with {client} failed: {err}"),
+            Err(_) => tracing::debug!("TLS handshake with {client} timed out"),
+        }
+    }
+
+    /// Serves requests on the connection until the client disconnects, a graceful shutdown ends it, or it's closed
+    /// shortly after the shutdown deadline
+    async fn serve<I>(self, stream: I, remote_addr: Option<SocketAddr>)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let ConnectionHandler {
+            builder,
+            router,
+            watcher,
+            shutdown_deadline,
+            unix_client_ip_header,
+            ..
+        } = self;
+
+        let request_shutdown_deadline: ShutdownDeadline = shutdown_deadline.clone();
+        let service = service_fn(move |mut request: Request<Incoming>| {
+            let client_ip: Option<ClientIp> = match remote_addr {
+                Some(remote_addr) => {
+                    request.extensions_mut().insert(ConnectInfo(remote_addr));
+                    Some(ClientIp(remote_addr.ip()))
+                }
+                None => unix_client_ip_header
+                    .as_ref()
+                    .and_then(|header| ClientIp::from_header(request.headers(), header)),
+            };
+            if let Some(client_ip) = client_ip {
+                request.extensions_mut().insert(client_ip);
+            }
+            request
+                .extensions_mut()
+                .insert(request_shutdown_deadline.clone());
+            router.clone().oneshot(request)
+        });
+        let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+
+        tokio::select! {
+            result = watcher.watch(connection.into_owned()) => {
+                if let Err(err) = result {
+                    tracing::debug!("Connection with {} failed: {err}", describe_client(remote_addr));
+                }
+            }
+            _ = async {
+                shutdown_deadline.reached().await;
+                tokio::time::sleep(FORCED_CLOSE_DELAY).await;
+            } => {
+                tracing::debug!("Closed the connection with {} at the shutdown deadline", describe_client(remote_addr));
+            }
+        }
+    }
+}
+
+/// Names the client for logs. Unix domain socket clients don't have an address.
+fn describe_client(remote_addr: Option<SocketAddr>) -> String {
+    remote_addr.map_or_else(
+        || String::from("a Unix socket client"),
+        |addr| addr.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{api::resources::ErrorResponse, services::timeouts};
+    use axum::{middleware, routing::get};
+    use pretty_assertions::assert_eq;
+    use tokio::net::TcpListener;
+
+    fn build_config(max_connections: Option<usize>) -> ConnectionConfig {
+        ConnectionConfig {
+            max_connections,
+            header_read_timeout: Duration::from_secs(30),
+            keep_alive: true,
+            http2_keep_alive_interval: Some(Duration::from_secs(20)),
+            graceful_shutdown_timeout: Duration::from_secs(10),
+            unix_client_ip_header: None,
+        }
+    }
+
+    /// Starts serving a router with a /test route on a random port, returning its address
+    async fn start_server(config: ConnectionConfig) -> SocketAddr {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let router: Router = Router::new().route("/test", get(|| async { "ok" }));
+        tokio::spawn(serve(
+            vec![listener.into()],
+            None,
+            router,
+            config,
+            std::future::pending(),
+        ));
+        addr
+    }
+
+    #[test]
+    fn serve_client_ip_from_header() {
+        let header = HeaderName::from_static("x-forwarded-for");
+        let mut headers = HeaderMap::new();
+        assert_eq!(ClientIp::from_header(&headers, &header), None);
+
+        // The proxy appends its client's address to any the client sent itself
+        headers.insert(&header, "203.0.113.1, 198.51.100.7".parse().unwrap());
+        assert_eq!(
+            ClientIp::from_header(&headers, &header),
+            Some(ClientIp([198, 51, 100, 7].into()))
+        );
+
+        headers.insert(&header, "unknown".parse().unwrap());
+        assert_eq!(ClientIp::from_header(&headers, &header), None);
+    }
+
+    #[tokio::test]
+    async fn serve_http1_and_h2c() {
+        let addr: SocketAddr = start_server(build_config(None)).await;
+
+        let response = reqwest::get(format!("http://{addr}/test")).await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_11);
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        // Internal hops can speak HTTP/2 without TLS, when they know we support it
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("http://{addr}/test"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    /// Validates clients past the connection limit wait until an open connection closes
+    #[tokio::test]
+    async fn serve_connection_limit() {
+        let addr: SocketAddr = start_server(build_config(Some(1))).await;
+        let request = || async move {
+            // A new client each time, so every request opens its own connection
+            reqwest::Client::new()
+                .get(format!("http://{addr}/test"))
+                .send()
+                .await
+        };
+
+        let idle_connection = tokio::net::TcpStream::connect(addr).await.unwrap();
+        // Gives the server time to accept the idle connection
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tokio::time::timeout(Duration::from_millis(500), request())
+            .await
+            .is_err());
+
+        drop(idle_connection);
+        let response = tokio::time::timeout(Duration::from_secs(5), request())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    /// Validates requests still in flight at the shutdown deadline are answered with a 503, rather than holding up
+    /// shutdown
+    #[tokio::test]
+    async fn serve_shutdown_deadline() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let router: Router = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    "ok"
+                }),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                Duration::from_secs(60),
+                timeouts::limit,
+            ));
+        let mut config: ConnectionConfig = build_config(None);
+        config.graceful_shutdown_timeout = Duration::from_millis(200);
+
+        let (shutdown, shutdown_signal) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(vec![listener.into()], None, router, config, async {
+            let _ = shutdown_signal.await;
+        }));
+        let request = tokio::spawn(reqwest::get(format!("http://{addr}/slow")));
+        // Gives the request time to reach the handler
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.send(()).unwrap();
+
+        let response = tokio::time::timeout(Duration::from_secs(5), request)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.status, 503);
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
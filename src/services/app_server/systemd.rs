@@ -0,0 +1,69 @@
+//! Integrates the app server with systemd, when it's run as a systemd service.
+//!
+//! With socket activation (a `.socket` unit), systemd binds the socket and passes it to us in the `LISTEN_FDS`
+//! environment variable, so the app server can be restarted without refusing connections. With `Type=notify`, we
+//! report when we're ready to serve and when we start shutting down, so systemd only considers the service started
+//! once requests can be served. Both do nothing when we weren't started by systemd.
+use std::{
+    io,
+    os::unix::net::UnixListener,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use listenfd::ListenFd;
+use sd_notify::NotifyState;
+
+/// Set once systemd is told we're stopping, so it's only told once
+static STOPPING_SENT: AtomicBool = AtomicBool::new(false);
+
+/// A listening socket passed to us by systemd
+pub enum InheritedSocket {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+
+/// Takes every socket passed with socket activation, none when we weren't started that way. They must be TCP or Unix
+/// stream sockets, e.g. from `ListenStream=` in the `.socket` unit.
+pub fn take_sockets() -> io::Result<Vec<InheritedSocket>> {
+    let mut listen_fd: ListenFd = ListenFd::from_env();
+    (0..listen_fd.len())
+        .map(|index| take_socket(&mut listen_fd, index))
+        .collect()
+}
+
+fn take_socket(listen_fd: &mut ListenFd, index: usize) -> io::Result<InheritedSocket> {
+    // Each take leaves the socket in place if it's of another kind
+    if let Ok(Some(listener)) = listen_fd.take_tcp_listener(index) {
+        return Ok(InheritedSocket::Tcp(listener));
+    }
+    match listen_fd.take_unix_listener(index) {
+        Ok(Some(listener)) => Ok(InheritedSocket::Unix(listener)),
+        Ok(None) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Socket {index} passed by systemd was already taken"),
+        )),
+        Err(err) => Err(io::Error::new(
+            err.kind(),
+            format!("Socket {index} passed by systemd isn't a TCP or Unix stream socket: {err}"),
+        )),
+    }
+}
+
+/// Tells systemd we're ready to serve requests
+pub fn notify_ready() {
+    notify(NotifyState::Ready);
+}
+
+/// Tells systemd we've started shutting down. Only the first call sends anything.
+pub fn notify_stopping() {
+    if !STOPPING_SENT.swap(true, Ordering::SeqCst) {
+        notify(NotifyState::Stopping);
+    }
+}
+
+fn notify(state: NotifyState) {
+    // Does nothing when NOTIFY_SOCKET isn't set, i.e. we weren't started by systemd
+    if let Err(err) = sd_notify::notify(false, &[state]) {
+        tracing::warn!("Failed to notify systemd: {err}");
+    }
+}
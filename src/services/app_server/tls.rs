@@ -0,0 +1,330 @@
+//! Provides TLS termination for the app server, so it can serve HTTPS without a proxy in front of it.
+//!
+//! TLS is turned on by setting `tls_cert_path` and `tls_key_path` to PEM files. The files are checked for changes every
+//! `tls_reload_interval`, and a changed certificate is used for new connections without dropping existing ones, so a
+//! renewed certificate (e.g. from cert-manager or certbot) is picked up without a restart. When `tls_redirect_server_url`
+//! is set, a plain HTTP listener redirects every request there to the same URL over HTTPS.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{Host, State},
+    http::Uri,
+    response::Redirect,
+    Router,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+use crate::services::configs::ConfigReader;
+
+/// Port browsers use for HTTPS URLs without one
+pub const DEFAULT_HTTPS_PORT: u16 = 443;
+
+/// Our TLS settings, from the tls_* config values
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf certificate first
+    pub cert_path: PathBuf,
+    /// PEM file with the certificate's private key
+    pub key_path: PathBuf,
+    /// How often the files are checked for changes
+    pub reload_interval: Duration,
+    /// Where to listen for plain HTTP requests to redirect to HTTPS. None turns the redirect off.
+    pub redirect_server_url: Option<String>,
+}
+
+impl TlsConfig {
+    /// Reads the tls_* config values. Returns None if TLS is turned off, i.e. `tls_cert_path` is empty.
+    pub fn from_config(reader: &mut ConfigReader) -> Option<Self> {
+        let cert_path: String = reader.get_optional_string("tls_cert_path")?;
+        let key_path: Option<String> = reader.get_optional_string("tls_key_path");
+        if key_path.is_none() {
+            reader.add_problem("tls_key_path", "is required when tls_cert_path is set");
+        }
+
+        let config = TlsConfig {
+            cert_path: PathBuf::from(cert_path),
+            key_path: PathBuf::from(key_path.clone().unwrap_or_default()),
+            reload_interval: reader.get_seconds("tls_reload_interval"),
+            redirect_server_url: reader.get_optional_string("tls_redirect_server_url"),
+        };
+
+        if config.reload_interval.is_zero() {
+            reader.add_problem("tls_reload_interval", "must be at least 1 second");
+        }
+        // So unreadable or mismatched files are reported along with every other config problem
+        if key_path.is_some() {
+            if let Err(err) = load_certified_key(&config) {
+                reader.add_problem("tls_cert_path", err);
+            }
+        }
+        Some(config)
+    }
+}
+
+/// Builds the acceptor that wraps connections in TLS, and starts watching the certificate files for changes. Needs to
+/// be called within a Tokio runtime.
+pub fn init_tls(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+    let cert_resolver: Arc<ReloadableCert> = Arc::new(ReloadableCert::load(config)?);
+
+    let mut server_config: ServerConfig = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(cert_resolver.clone());
+    // HTTP/2 first, so clients that support it multiplex their requests over one connection
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    tokio::spawn(watch_for_changes(cert_resolver, config.clone()));
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Serves our certificate to every TLS handshake, swapping in a new one when its files change
+#[derive(Debug)]
+struct ReloadableCert(RwLock<Arc<CertifiedKey>>);
+
+impl ReloadableCert {
+    fn load(config: &TlsConfig) -> Result<Self, String> {
+        Ok(ReloadableCert(RwLock::new(Arc::new(load_certified_key(
+            config,
+        )?))))
+    }
+
+    /// Replaces the certificate with the one in the files. Connections already using the previous one keep it.
+    fn reload(&self, config: &TlsConfig) -> Result<(), String> {
+        let certified_key: CertifiedKey = load_certified_key(config)?;
+        *self.0.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+
+    fn get(&self) -> Arc<CertifiedKey> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.get())
+    }
+}
+
+/// Loads the certificate chain and private key from their PEM files, checking the key belongs to the certificate
+fn load_certified_key(config: &TlsConfig) -> Result<CertifiedKey, String> {
+    let cert_chain: Vec<CertificateDer<'static>> = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(|certs| certs.collect())
+        .map_err(|err| format!("{} can't be read, {err}", config.cert_path.display()))?;
+    if cert_chain.is_empty() {
+        return Err(format!(
+            "{} has no certificates",
+            config.cert_path.display()
+        ));
+    }
+
+    let key: PrivateKeyDer<'static> = PrivateKeyDer::from_pem_file(&config.key_path)
+        .map_err(|err| format!("{} can't be read, {err}", config.key_path.display()))?;
+
+    CertifiedKey::from_der(cert_chain, key, &ring::default_provider()).map_err(|err| {
+        format!(
+            "{} doesn't hold the private key of {}, {err}",
+            config.key_path.display(),
+            config.cert_path.display()
+        )
+    })
+}
+
+/// When the certificate and key files were last changed, or None if either can't be read
+fn modified_at(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let modified_at = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    Some((
+        modified_at(&config.cert_path).ok()?,
+        modified_at(&config.key_path).ok()?,
+    ))
+}
+
+/// Reloads the certificate whenever its files change. A certificate that fails to load is logged and the previous
+/// one kept, e.g. when the files are caught halfway through being replaced, which gets retried on their next change.
+async fn watch_for_changes(cert_resolver: Arc<ReloadableCert>, config: TlsConfig) {
+    let mut last_modified_at: Option<(SystemTime, SystemTime)> = modified_at(&config);
+    let mut interval = tokio::time::interval(config.reload_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes right away
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let modified_at: Option<(SystemTime, SystemTime)> = modified_at(&config);
+        if modified_at == last_modified_at {
+            continue;
+        }
+        last_modified_at = modified_at;
+
+        match cert_resolver.reload(&config) {
+            Ok(()) => tracing::info!(
+                "Reloaded the TLS certificate from {}",
+                config.cert_path.display()
+            ),
+            Err(err) => tracing::error!(
+                "Failed to reload the TLS certificate, still using the previous one: {err}"
+            ),
+        }
+    }
+}
+
+/// Returns the routes of our plain HTTP listener, which redirect every request to the same URL over HTTPS on the port
+pub fn redirect_routes(https_port: u16) -> Router {
+    Router::new()
+        .fallback(redirect_to_https)
+        .with_state(https_port)
+}
+
+async fn redirect_to_https(State(https_port): State<u16>, Host(host): Host, uri: Uri) -> Redirect {
+    // Drop the plain HTTP port, taking care of IPv6 addresses like "[::1]:80"
+    let hostname: &str = match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => &host,
+    };
+    let path_and_query: &str = uri
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+
+    if https_port == DEFAULT_HTTPS_PORT {
+        Redirect::permanent(&format!("https://{hostname}{path_and_query}"))
+    } else {
+        Redirect::permanent(&format!("https://{hostname}:{https_port}{path_and_query}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::services::{app_server::serve, configs::AppConfig};
+    use axum::{
+        http::{header, HeaderValue, StatusCode},
+        routing::get,
+    };
+    use axum_test::TestServer;
+    use pretty_assertions::assert_eq;
+    use tokio::net::TcpListener;
+
+    /// Writes a new self-signed certificate for localhost and its key to the files in the config
+    fn write_self_signed_cert(config: &TlsConfig) -> CertificateDer<'static> {
+        let generated =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        fs::write(&config.cert_path, generated.cert.pem()).unwrap();
+        fs::write(&config.key_path, generated.key_pair.serialize_pem()).unwrap();
+        generated.cert.der().clone()
+    }
+
+    fn build_config() -> TlsConfig {
+        let dir: PathBuf = std::env::temp_dir().join(format!("tls_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            reload_interval: Duration::from_secs(1),
+            redirect_server_url: None,
+        }
+    }
+
+    #[test]
+    fn tls_reload_cert() {
+        let config: TlsConfig = build_config();
+        let first_cert: CertificateDer = write_self_signed_cert(&config);
+        let cert_resolver: ReloadableCert = ReloadableCert::load(&config).unwrap();
+        assert_eq!(cert_resolver.get().cert[0], first_cert);
+
+        let second_cert: CertificateDer = write_self_signed_cert(&config);
+        cert_resolver.reload(&config).unwrap();
+        assert_eq!(cert_resolver.get().cert[0], second_cert);
+
+        // A key that doesn't belong to the certificate is rejected, and the current certificate kept
+        let other_config: TlsConfig = build_config();
+        write_self_signed_cert(&other_config);
+        fs::copy(&other_config.key_path, &config.key_path).unwrap();
+        let err: String = cert_resolver.reload(&config).unwrap_err();
+        assert!(err.contains("doesn't hold the private key"), "{err}");
+        assert_eq!(cert_resolver.get().cert[0], second_cert);
+
+        for config in [config, other_config] {
+            fs::remove_dir_all(config.cert_path.parent().unwrap()).unwrap();
+        }
+    }
+
+    /// Validates requests are served over TLS, with HTTP/2 negotiated by clients that support it
+    #[tokio::test]
+    async fn tls_serve() {
+        let config: TlsConfig = build_config();
+        write_self_signed_cert(&config);
+
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router: Router = Router::new().route("/test", get(|| async { "ok" }));
+        tokio::spawn(serve::serve(
+            vec![listener.into()],
+            Some(init_tls(&config).unwrap()),
+            router,
+            AppConfig::load().unwrap().app_server_connections,
+            std::future::pending(),
+        ));
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("https://localhost:{}/test", addr.port()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        // Plain HTTP isn't served
+        assert!(
+            reqwest::get(format!("http://localhost:{}/test", addr.port()))
+                .await
+                .is_err()
+        );
+
+        fs::remove_dir_all(config.cert_path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tls_redirect_to_https() {
+        let server = TestServer::new(redirect_routes(8443)).unwrap();
+        let response = server
+            .get("/api/players?page=2")
+            .add_header(header::HOST, HeaderValue::from_static("example.com:8080"))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.header(header::LOCATION),
+            "https://example.com:8443/api/players?page=2"
+        );
+
+        let server = TestServer::new(redirect_routes(443)).unwrap();
+        let response = server
+            .get("/")
+            .add_header(header::HOST, HeaderValue::from_static("[::1]"))
+            .await;
+        assert_eq!(response.header(header::LOCATION), "https://[::1]/");
+    }
+}
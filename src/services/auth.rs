@@ -0,0 +1,264 @@
+//! Provides authentication and authorization for our APIs.
+//!
+//! Every API request is resolved to a [`Principal`] by the [`authenticate`] middleware. Callers identify themselves
+//! with either an `Authorization: Bearer <key>` header holding an API key (see [`api_keys`]), or the session cookie a
+//! user gets by logging in with single sign-on (see [`oidc`] and [`sessions`]). Requests without credentials are
+//! anonymous and get the scopes listed in the `auth_anonymous_scopes` config value.
+//!
+//! Routes declare the [`Scope`] they need with the [`require_scope`] middleware. This is the single authorization check
+//! every kind of principal goes through, so a new way to log in only needs to produce a [`Principal`].
+pub mod api_keys;
+pub mod oidc;
+pub mod sessions;
+
+use std::{fmt, str::FromStr};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{api::errors::ApiError, services::app_server::AppState};
+
+/// A permission to perform a group of operations on our APIs
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    #[serde(rename = "players:read")]
+    PlayersRead,
+    #[serde(rename = "players:write")]
+    PlayersWrite,
+    /// Grants every other scope, plus access to our admin APIs
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::PlayersRead => "players:read",
+            Scope::PlayersWrite => "players:write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "players:read" => Ok(Scope::PlayersRead),
+            "players:write" => Ok(Scope::PlayersWrite),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("unknown scope \"{value}\"")),
+        }
+    }
+}
+
+/// Parses a comma separated list of scopes, e.g. "players:read,players:write". An empty string is no scopes.
+pub fn parse_scopes(value: &str) -> Result<Vec<Scope>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|scope| !scope.is_empty())
+        .map(Scope::from_str)
+        .collect()
+}
+
+/// Who is making a request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum Subject {
+    Anonymous,
+    ApiKey(Uuid),
+    User(Uuid),
+}
+
+/// The authenticated caller of a request and the scopes they've been granted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Principal {
+    pub subject: Subject,
+    pub scopes: Vec<Scope>,
+}
+
+impl Principal {
+    /// A caller that didn't provide any credentials, granted the `auth_anonymous_scopes` config value
+    pub fn anonymous(scopes: &[Scope]) -> Self {
+        Principal {
+            subject: Subject::Anonymous,
+            scopes: scopes.to_vec(),
+        }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    /// Returns a 401 error for anonymous callers and a 403 error for authenticated callers that lack the scope
+    pub fn authorize(&self, scope: Scope) -> Result<(), ApiError> {
+        if self.has_scope(scope) {
+            return Ok(());
+        }
+
+        match self.subject {
+            Subject::Anonymous => Err(ApiError::unauthorized(format!(
+                "Credentials with the \"{scope}\" scope are required"
+            ))),
+            _ => Err(ApiError::forbidden(format!(
+                "Credentials are missing the \"{scope}\" scope"
+            ))),
+        }
+    }
+}
+
+/// Middleware that resolves the [`Principal`] of a request and adds it to the request's extensions, rejecting requests
+/// with invalid credentials.
+pub async fn authenticate(
+    State(app_state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let anonymous_scopes: &[Scope] = &app_state.config.auth_anonymous_scopes;
+    let principal: Principal = match bearer_token(request.headers()) {
+        Ok(None) => match sessions::get_session_token(request.headers()) {
+            // An unknown or expired session just means the user needs to log in again, so isn't an error
+            Some(token) => match sessions::authenticate(&app_state.db_pool, token).await {
+                Ok(principal) => {
+                    principal.unwrap_or_else(|| Principal::anonymous(anonymous_scopes))
+                }
+                Err(err) => return ApiError::from(err).into_response(),
+            },
+            None => Principal::anonymous(anonymous_scopes),
+        },
+        Ok(Some(key)) => match api_keys::authenticate(&app_state.db_pool, key).await {
+            Ok(Some(principal)) => principal,
+            Ok(None) => {
+                return ApiError::unauthorized("Invalid or revoked API key").into_response()
+            }
+            Err(err) => return ApiError::from(err).into_response(),
+        },
+        Err(err) => return err.into_response(),
+    };
+
+    request.extensions_mut().insert(principal);
+    next.run(request).await
+}
+
+/// Middleware that rejects requests whose [`Principal`] wasn't granted the scope. Add it to a route with:
+/// `.route_layer(middleware::from_fn_with_state(Scope::PlayersRead, auth::require_scope))`
+pub async fn require_scope(State(scope): State<Scope>, request: Request, next: Next) -> Response {
+    let authorized: Result<(), ApiError> = match request.extensions().get::<Principal>() {
+        Some(principal) => principal.authorize(scope),
+        // Means the authenticate middleware above wasn't added in front of this route
+        None => Err(ApiError::internal("Request was not authenticated")),
+    };
+
+    match authorized {
+        Ok(()) => next.run(request).await,
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Generates a random, hex encoded token from the number of random bytes
+fn generate_token(num_bytes: usize) -> String {
+    let mut bytes: Vec<u8> = vec![0u8; num_bytes];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Returns the hex encoded SHA-256 hash of a secret token, which is what we store and look tokens up by. Since our
+/// tokens are long random values (unlike passwords), a single fast hash is enough.
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Returns the token from an `Authorization: Bearer <token>` header, if there is one
+fn bearer_token(headers: &HeaderMap) -> Result<Option<&str>, ApiError> {
+    let Some(value) = headers.get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| Some(token.trim()))
+        .ok_or_else(|| {
+            ApiError::unauthorized("Authorization header must be of the form \"Bearer <key>\"")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use axum::http::{HeaderValue, StatusCode};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn auth_parse_scopes() {
+        assert_eq!(
+            parse_scopes("players:read, players:write").unwrap(),
+            vec![Scope::PlayersRead, Scope::PlayersWrite]
+        );
+        assert_eq!(parse_scopes("").unwrap(), vec![]);
+        assert!(parse_scopes("players:read,players:delete").is_err());
+    }
+
+    #[test]
+    fn auth_admin_grants_every_scope() {
+        let principal = Principal {
+            subject: Subject::ApiKey(Uuid::new_v4()),
+            scopes: vec![Scope::Admin],
+        };
+        assert!(principal.authorize(Scope::PlayersWrite).is_ok());
+    }
+
+    #[test]
+    fn auth_missing_scope_status() {
+        let anonymous = Principal {
+            subject: Subject::Anonymous,
+            scopes: vec![],
+        };
+        assert_eq!(
+            anonymous.authorize(Scope::Admin).unwrap_err().status,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let api_key = Principal {
+            subject: Subject::ApiKey(Uuid::new_v4()),
+            scopes: vec![Scope::PlayersRead],
+        };
+        assert_eq!(
+            api_key.authorize(Scope::Admin).unwrap_err().status,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn auth_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers).unwrap(), None);
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer abc"),
+        );
+        assert_eq!(bearer_token(&headers).unwrap(), Some("abc"));
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic abc"));
+        assert!(bearer_token(&headers).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Provides hashed, revocable API keys for scripts and service-to-service callers that can't use a browser login.
+//!
+//! Keys look like `px_<prefix>_<secret>`. Only a hash of the full key is stored, the non-secret prefix is kept so admins
+//! can tell keys apart.
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::{
+    resources::{ApiKey, CreatedApiKey},
+    services::db,
+};
+
+use super::{generate_token, hash_token, Principal, Scope, Subject};
+
+const KEY_PREFIX: &str = "px";
+
+/// An api_key row as it's stored in the DB
+struct ApiKeyRow {
+    id: Uuid,
+    name: String,
+    prefix: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> Self {
+        ApiKey {
+            id: row.id,
+            name: row.name,
+            prefix: row.prefix,
+            scopes: to_scopes(&row.scopes),
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+            revoked_at: row.revoked_at,
+        }
+    }
+}
+
+/// Creates a new API key with the scopes. The returned [`CreatedApiKey`] is the only place the full key is available.
+pub async fn create_api_key(
+    db_pool: &Pool<Postgres>,
+    name: &str,
+    scopes: &[Scope],
+) -> Result<CreatedApiKey, sqlx::Error> {
+    let (prefix, key) = generate_key();
+    let scopes: Vec<String> = scopes.iter().map(|scope| scope.to_string()).collect();
+
+    let row: ApiKeyRow = sqlx::query_as!(
+        ApiKeyRow,
+        r#"INSERT INTO api_key
+        (name, prefix, key_hash, scopes)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, name, prefix, scopes, created_at, last_used_at, revoked_at"#,
+        name,
+        prefix,
+        hash_token(&key),
+        &scopes
+    )
+    .fetch_one(db_pool)
+    .instrument(db::query_span("INSERT api_key"))
+    .await?;
+
+    Ok(CreatedApiKey {
+        api_key: row.into(),
+        key,
+    })
+}
+
+/// Returns all API keys, including revoked ones
+pub async fn get_api_keys(db_pool: &Pool<Postgres>) -> Result<Vec<ApiKey>, sqlx::Error> {
+    let rows: Vec<ApiKeyRow> = sqlx::query_as!(
+        ApiKeyRow,
+        "select id, name, prefix, scopes, created_at, last_used_at, revoked_at from api_key order by created_at"
+    )
+    .fetch_all(db_pool)
+    .instrument(db::query_span("SELECT api_key"))
+    .await?;
+
+    Ok(rows.into_iter().map(ApiKey::from).collect())
+}
+
+/// Revokes an API key so it can no longer be used. Returns [`sqlx::Error::RowNotFound`] if there's no such key.
+pub async fn revoke_api_key(db_pool: &Pool<Postgres>, id: Uuid) -> Result<ApiKey, sqlx::Error> {
+    let row: ApiKeyRow = sqlx::query_as!(
+        ApiKeyRow,
+        r#"UPDATE api_key
+        SET revoked_at = coalesce(revoked_at, now())
+        WHERE id = $1
+        RETURNING id, name, prefix, scopes, created_at, last_used_at, revoked_at"#,
+        id
+    )
+    .fetch_one(db_pool)
+    .instrument(db::query_span("UPDATE api_key"))
+    .await?;
+
+    Ok(row.into())
+}
+
+/// Looks up the [`Principal`] for an API key, recording that the key was used. Returns None if the key doesn't exist
+/// or was revoked.
+pub async fn authenticate(
+    db_pool: &Pool<Postgres>,
+    key: &str,
+) -> Result<Option<Principal>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"UPDATE api_key
+        SET last_used_at = now()
+        WHERE key_hash = $1 AND revoked_at IS NULL
+        RETURNING id, scopes"#,
+        hash_token(key)
+    )
+    .fetch_optional(db_pool)
+    .instrument(db::query_span("UPDATE api_key"))
+    .await?;
+
+    Ok(row.map(|row| Principal {
+        subject: Subject::ApiKey(row.id),
+        scopes: to_scopes(&row.scopes),
+    }))
+}
+
+/// Generates a new random key, returning its non-secret prefix and the full key
+fn generate_key() -> (String, String) {
+    let prefix: String = format!("{}_{}", KEY_PREFIX, generate_token(4));
+    let key: String = format!("{}_{}", prefix, generate_token(24));
+    (prefix, key)
+}
+
+/// Converts scopes stored in the DB, skipping any that are no longer known to this version of the app
+pub(super) fn to_scopes(scopes: &[String]) -> Vec<Scope> {
+    scopes
+        .iter()
+        .filter_map(|scope| scope.parse::<Scope>().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::DB_MIGRATOR;
+    use pretty_assertions::assert_eq;
+    use sqlx::PgPool;
+
+    #[test]
+    fn api_keys_generate_key() {
+        let (prefix, key) = generate_key();
+        assert!(key.starts_with(&format!("{prefix}_")));
+        assert_ne!(generate_key().1, key);
+        assert_eq!(hash_token(&key).len(), 64);
+    }
+
+    /// Validates the full lifecycle of a key: create, use, revoke
+    #[sqlx::test(migrator = "DB_MIGRATOR")]
+    async fn api_keys_authenticate(pool: PgPool) {
+        let created: CreatedApiKey = create_api_key(&pool, "ingest", &[Scope::PlayersRead])
+            .await
+            .unwrap();
+        assert!(created.api_key.last_used_at.is_none());
+
+        let principal: Principal = authenticate(&pool, &created.key).await.unwrap().unwrap();
+        assert_eq!(principal.subject, Subject::ApiKey(created.api_key.id));
+        assert_eq!(principal.scopes, vec![Scope::PlayersRead]);
+
+        let api_keys: Vec<ApiKey> = get_api_keys(&pool).await.unwrap();
+        assert!(api_keys.first().unwrap().last_used_at.is_some());
+
+        // An unknown key isn't authenticated
+        assert!(authenticate(&pool, "px_00000000_unknown")
+            .await
+            .unwrap()
+            .is_none());
+
+        let revoked: ApiKey = revoke_api_key(&pool, created.api_key.id).await.unwrap();
+        assert!(revoked.revoked_at.is_some());
+        assert!(authenticate(&pool, &created.key).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,672 @@
+//! Provides single sign-on against an external OpenID Connect (OIDC) identity provider, using the authorization code
+//! flow with PKCE (<https://openid.net/specs/openid-connect-core-1_0.html#CodeFlowAuth>).
+//!
+//! The flow is:
+//! 1. [`begin_login`] remembers a random `state`, `nonce` and PKCE code verifier, and returns the URL of the provider's
+//!    login page to redirect the user's browser to. The `state` is also given to the browser in a cookie (see
+//!    [`sessions`](super::sessions)), so only the browser that started the login can complete it.
+//! 2. Once the user logs in, the provider redirects back to our `oidc_redirect_url` with a code, which
+//!    [`complete_login`] exchanges for the user's ID token. The user is then provisioned as a local user on their first
+//!    login, or linked to an existing local user with the same verified email.
+//!
+//! The ID token comes straight from the provider's token endpoint over a connection we opened, so per the OIDC spec
+//! (section 3.1.3.7) we rely on TLS to validate where it came from instead of checking its signature. That's why the
+//! provider's endpoints must use https, except on a loopback address for local development and testing.
+//!
+//! Single sign-on is turned on by setting `oidc_issuer_url` (along with the client settings) in our config.
+use std::{fmt, net::IpAddr, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::services::{
+    configs::{secret::Secret, ConfigReader},
+    db,
+};
+
+use super::{generate_token, Scope};
+
+/// How long a user has to complete a login at the identity provider
+pub const LOGIN_MAX_AGE: Duration = Duration::from_secs(600);
+
+/// Settings for our client registration at the identity provider
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Secret,
+    /// Our URL the provider redirects users back to, which must be registered with the provider
+    pub redirect_url: String,
+}
+
+impl OidcConfig {
+    /// Reads the oidc_* config values. Returns None if single sign-on isn't turned on.
+    pub fn from_config(reader: &mut ConfigReader) -> Option<Self> {
+        let issuer_url: String = reader.get_optional_string("oidc_issuer_url")?;
+
+        let client_id: Option<String> = reader.get_optional_string("oidc_client_id");
+        let client_secret: Option<Secret> = reader.get_optional_secret("oidc_client_secret");
+        let redirect_url: Option<String> = reader.get_optional_string("oidc_redirect_url");
+
+        for (key, is_set) in [
+            ("oidc_client_id", client_id.is_some()),
+            ("oidc_client_secret", client_secret.is_some()),
+            ("oidc_redirect_url", redirect_url.is_some()),
+        ] {
+            if !is_set {
+                reader.add_problem(key, "is required when oidc_issuer_url is set");
+            }
+        }
+
+        Some(OidcConfig {
+            issuer_url,
+            client_id: client_id.unwrap_or_default(),
+            client_secret: client_secret.unwrap_or_default(),
+            redirect_url: redirect_url.unwrap_or_default(),
+        })
+    }
+}
+
+/// Reasons a login can fail
+#[derive(Debug)]
+pub enum OidcError {
+    /// We couldn't talk to the identity provider, or it returned something unexpected
+    Provider(String),
+    /// The login itself isn't valid, e.g. it expired or the ID token doesn't match what we asked for
+    InvalidLogin(String),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Provider(message) => write!(f, "Identity provider error: {message}"),
+            OidcError::InvalidLogin(message) => write!(f, "Invalid login: {message}"),
+            OidcError::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for OidcError {
+    fn from(err: sqlx::Error) -> Self {
+        OidcError::Database(err)
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(err: reqwest::Error) -> Self {
+        OidcError::Provider(err.to_string())
+    }
+}
+
+/// The subset of the provider's discovery document we use:
+/// <https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata>
+#[derive(Deserialize, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The ID token's audience can be a single client id or a list of them
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(audience) => audience == client_id,
+            Audience::Many(audiences) => audiences.iter().any(|audience| audience == client_id),
+        }
+    }
+}
+
+/// The claims we use from the ID token: <https://openid.net/specs/openid-connect-core-1_0.html#IDToken>
+#[derive(Deserialize, Debug)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    name: Option<String>,
+}
+
+/// A login that's been started
+#[derive(Debug)]
+pub struct LoginStart {
+    /// The identity provider's login page to redirect the user to
+    pub authorization_url: Url,
+    /// Identifies the login, which the browser that started it must hold in a cookie to complete it
+    pub state: String,
+}
+
+/// Starts a login, returning the URL of the identity provider's login page to redirect the user to
+pub async fn begin_login(
+    db_pool: &Pool<Postgres>,
+    config: &OidcConfig,
+) -> Result<LoginStart, OidcError> {
+    let metadata: ProviderMetadata = discover(config).await?;
+
+    let state: String = generate_token(16);
+    let nonce: String = generate_token(16);
+    let code_verifier: String = generate_token(32);
+
+    // Clean up logins that were abandoned, then remember this one
+    sqlx::query!(
+        "delete from oidc_login where created_at < now() - make_interval(secs => $1)",
+        LOGIN_MAX_AGE.as_secs_f64()
+    )
+    .execute(db_pool)
+    .instrument(db::query_span("DELETE oidc_login"))
+    .await?;
+    sqlx::query!(
+        "INSERT INTO oidc_login (state, code_verifier, nonce) VALUES ($1, $2, $3)",
+        state,
+        code_verifier,
+        nonce
+    )
+    .execute(db_pool)
+    .instrument(db::query_span("INSERT oidc_login"))
+    .await?;
+
+    let authorization_url: Url = Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", config.redirect_url.as_str()),
+            ("scope", "openid email profile"),
+            ("state", state.as_str()),
+            ("nonce", nonce.as_str()),
+            (
+                "code_challenge",
+                build_code_challenge(&code_verifier).as_str(),
+            ),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|err| OidcError::Provider(format!("invalid authorization_endpoint: {err}")))?;
+
+    Ok(LoginStart {
+        authorization_url,
+        state,
+    })
+}
+
+/// Completes a login with the code and state the identity provider redirected the user back with, returning the id
+/// of the local user that logged in. Users provisioned on their first login are granted the default scopes.
+pub async fn complete_login(
+    db_pool: &Pool<Postgres>,
+    config: &OidcConfig,
+    default_scopes: &[Scope],
+    code: &str,
+    state: &str,
+) -> Result<Uuid, OidcError> {
+    // Each login can only be completed once
+    let login = sqlx::query!(
+        r#"DELETE FROM oidc_login
+        WHERE state = $1 AND created_at > now() - make_interval(secs => $2)
+        RETURNING code_verifier, nonce"#,
+        state,
+        LOGIN_MAX_AGE.as_secs_f64()
+    )
+    .fetch_optional(db_pool)
+    .instrument(db::query_span("DELETE oidc_login"))
+    .await?
+    .ok_or_else(|| OidcError::InvalidLogin(String::from("unknown or expired login")))?;
+
+    let metadata: ProviderMetadata = discover(config).await?;
+
+    let response = reqwest::Client::new()
+        .post(&metadata.token_endpoint)
+        .basic_auth(&config.client_id, Some(config.client_secret.expose()))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", config.redirect_url.as_str()),
+            ("code_verifier", login.code_verifier.as_str()),
+        ])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(OidcError::Provider(format!(
+            "token endpoint returned {}",
+            response.status()
+        )));
+    }
+    let token: TokenResponse = response.json().await?;
+
+    let claims: IdTokenClaims = decode_id_token(&token.id_token)?;
+    validate_claims(
+        &claims,
+        &metadata.issuer,
+        &config.client_id,
+        &login.nonce,
+        chrono::Utc::now().timestamp(),
+    )?;
+
+    Ok(provision_user(db_pool, &claims, default_scopes).await?)
+}
+
+/// Fetches the identity provider's discovery document
+async fn discover(config: &OidcConfig) -> Result<ProviderMetadata, OidcError> {
+    let discovery_url: String = format!(
+        "{}/.well-known/openid-configuration",
+        config.issuer_url.trim_end_matches('/')
+    );
+    validate_endpoint(&discovery_url)?;
+
+    let metadata: ProviderMetadata = reqwest::get(&discovery_url)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    if metadata.issuer.trim_end_matches('/') != config.issuer_url.trim_end_matches('/') {
+        return Err(OidcError::Provider(format!(
+            "discovery document is for issuer {}",
+            metadata.issuer
+        )));
+    }
+    validate_endpoint(&metadata.authorization_endpoint)?;
+    validate_endpoint(&metadata.token_endpoint)?;
+
+    Ok(metadata)
+}
+
+/// Endpoints must use https (see the module docs for why), except on a loopback address
+fn validate_endpoint(endpoint: &str) -> Result<(), OidcError> {
+    let url: Url = Url::parse(endpoint)
+        .map_err(|err| OidcError::Provider(format!("invalid endpoint {endpoint}: {err}")))?;
+
+    let is_loopback: bool = match url.host_str() {
+        Some("localhost") => true,
+        Some(host) => host
+            .trim_matches(['[', ']'])
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    };
+
+    if url.scheme() == "https" || is_loopback {
+        Ok(())
+    } else {
+        Err(OidcError::Provider(format!(
+            "endpoint {endpoint} must use https"
+        )))
+    }
+}
+
+/// The PKCE code challenge for a code verifier, using the S256 method: <https://datatracker.ietf.org/doc/html/rfc7636>
+fn build_code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Reads the claims from the ID token, which is a JWT of the form `<header>.<claims>.<signature>`
+fn decode_id_token(id_token: &str) -> Result<IdTokenClaims, OidcError> {
+    let claims: &str = id_token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| OidcError::Provider(String::from("ID token is not a JWT")))?;
+
+    let claims: Vec<u8> = URL_SAFE_NO_PAD
+        .decode(claims.trim_end_matches('='))
+        .map_err(|err| OidcError::Provider(format!("ID token is not a JWT: {err}")))?;
+
+    serde_json::from_slice(&claims)
+        .map_err(|err| OidcError::Provider(format!("ID token is missing claims: {err}")))
+}
+
+/// Validates the ID token was issued by our provider, for us, for this login, and hasn't expired
+fn validate_claims(
+    claims: &IdTokenClaims,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+    now: i64,
+) -> Result<(), OidcError> {
+    if claims.iss != issuer {
+        return Err(OidcError::InvalidLogin(format!(
+            "ID token was issued by {}",
+            claims.iss
+        )));
+    }
+    if !claims.aud.contains(client_id) {
+        return Err(OidcError::InvalidLogin(String::from(
+            "ID token was issued for another client",
+        )));
+    }
+    if claims.exp <= now {
+        return Err(OidcError::InvalidLogin(String::from(
+            "ID token has expired",
+        )));
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(OidcError::InvalidLogin(String::from(
+            "ID token is for another login",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the local user linked to the identity in the ID token. On the identity's first login, it's linked to the
+/// local user with the same email if the provider verified it, otherwise a new local user is provisioned.
+async fn provision_user(
+    db_pool: &Pool<Postgres>,
+    claims: &IdTokenClaims,
+    default_scopes: &[Scope],
+) -> Result<Uuid, sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+
+    let linked_user_id: Option<Uuid> = sqlx::query_scalar!(
+        "select user_id from user_identity where issuer = $1 and subject = $2",
+        claims.iss,
+        claims.sub
+    )
+    .fetch_optional(&mut *transaction)
+    .instrument(db::query_span("SELECT user_identity"))
+    .await?;
+
+    let user_id: Uuid = match linked_user_id {
+        Some(user_id) => user_id,
+        None => {
+            // Only trust an email to identify a user if the provider verified it
+            let verified_email: Option<&str> = match claims.email_verified {
+                Some(true) => claims.email.as_deref(),
+                _ => None,
+            };
+
+            let existing_user_id: Option<Uuid> = match verified_email {
+                Some(email) => {
+                    sqlx::query_scalar!("select id from app_user where email = $1", email)
+                        .fetch_optional(&mut *transaction)
+                        .instrument(db::query_span("SELECT app_user"))
+                        .await?
+                }
+                None => None,
+            };
+
+            let user_id: Uuid = match existing_user_id {
+                Some(user_id) => user_id,
+                None => {
+                    let scopes: Vec<String> = default_scopes
+                        .iter()
+                        .map(|scope| scope.to_string())
+                        .collect();
+                    sqlx::query_scalar!(
+                        "INSERT INTO app_user (email, name, scopes) VALUES ($1, $2, $3) RETURNING id",
+                        verified_email,
+                        claims.name,
+                        &scopes
+                    )
+                    .fetch_one(&mut *transaction)
+                    .instrument(db::query_span("INSERT app_user"))
+                    .await?
+                }
+            };
+
+            sqlx::query!(
+                "INSERT INTO user_identity (issuer, subject, user_id) VALUES ($1, $2, $3)",
+                claims.iss,
+                claims.sub,
+                user_id
+            )
+            .execute(&mut *transaction)
+            .instrument(db::query_span("INSERT user_identity"))
+            .await?;
+
+            tracing::debug!(
+                "Linked identity {} from {} to user {user_id}",
+                claims.sub,
+                claims.iss
+            );
+            user_id
+        }
+    };
+
+    sqlx::query!(
+        "update app_user set last_login_at = now() where id = $1",
+        user_id
+    )
+    .execute(&mut *transaction)
+    .instrument(db::query_span("UPDATE app_user"))
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::DB_MIGRATOR;
+    use axum::{extract::State, http::StatusCode, routing, Form, Json, Router};
+    use pretty_assertions::assert_eq;
+    use sqlx::PgPool;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    const MOCK_CODE: &str = "mock-code";
+
+    /// What the mock identity provider knows about the login in progress
+    #[derive(Clone, Default)]
+    struct MockLogin {
+        issuer: String,
+        subject: String,
+        email: Option<String>,
+        nonce: String,
+        code_challenge: String,
+    }
+
+    type MockState = Arc<Mutex<MockLogin>>;
+
+    /// Starts a mock identity provider on a random local port, returning the config to use it
+    async fn start_mock_provider(login: MockState) -> OidcConfig {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer_url: String = format!("http://{}", listener.local_addr().unwrap());
+        login.lock().unwrap().issuer = issuer_url.clone();
+
+        let router: Router = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                routing::get(mock_discovery),
+            )
+            .route("/token", routing::post(mock_token))
+            .with_state(login);
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        OidcConfig {
+            issuer_url,
+            client_id: String::from("hello-world"),
+            client_secret: Secret::new("secret"),
+            redirect_url: String::from("http://127.0.0.1:3000/api/auth/callback"),
+        }
+    }
+
+    async fn mock_discovery(State(login): State<MockState>) -> Json<serde_json::Value> {
+        let issuer: String = login.lock().unwrap().issuer.clone();
+        Json(serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+        }))
+    }
+
+    /// Only issues an ID token if the code and PKCE code verifier match the login
+    async fn mock_token(
+        State(login): State<MockState>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<serde_json::Value>, StatusCode> {
+        let login: MockLogin = login.lock().unwrap().clone();
+        if form["code"] != MOCK_CODE
+            || build_code_challenge(&form["code_verifier"]) != login.code_challenge
+        {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let claims = serde_json::json!({
+            "iss": login.issuer,
+            "sub": login.subject,
+            "aud": "hello-world",
+            "exp": chrono::Utc::now().timestamp() + 60,
+            "nonce": login.nonce,
+            "email": login.email,
+            "email_verified": true,
+            "name": "Kurt Rambis",
+        });
+        let id_token: String = format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+
+        Ok(Json(
+            serde_json::json!({ "access_token": "access", "token_type": "Bearer", "id_token": id_token }),
+        ))
+    }
+
+    /// Runs a full login as the user our mock identity provider has logged in, returning the local user's id
+    async fn login(pool: &PgPool, config: &OidcConfig, mock_login: &MockState) -> Uuid {
+        let authorization_url: Url = begin_login(pool, config).await.unwrap().authorization_url;
+        let params: HashMap<String, String> =
+            authorization_url.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        {
+            let mut mock_login = mock_login.lock().unwrap();
+            mock_login.nonce = params["nonce"].clone();
+            mock_login.code_challenge = params["code_challenge"].clone();
+        }
+
+        let user_id: Uuid = complete_login(pool, config, &[], MOCK_CODE, &params["state"])
+            .await
+            .unwrap();
+
+        // A login can't be completed twice
+        assert!(matches!(
+            complete_login(pool, config, &[], MOCK_CODE, &params["state"]).await,
+            Err(OidcError::InvalidLogin(_))
+        ));
+
+        user_id
+    }
+
+    #[sqlx::test(migrator = "DB_MIGRATOR")]
+    async fn oidc_login_provisions_user(pool: PgPool) {
+        let mock_login: MockState = Arc::new(Mutex::new(MockLogin {
+            subject: String::from("rambo"),
+            email: Some(String::from("kurt@lakers.com")),
+            ..Default::default()
+        }));
+        let config: OidcConfig = start_mock_provider(mock_login.clone()).await;
+
+        let user_id: Uuid = login(&pool, &config, &mock_login).await;
+        let email: Option<String> = sqlx::query_scalar("select email from app_user where id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(email.as_deref(), Some("kurt@lakers.com"));
+
+        // Logging in again is the same user
+        assert_eq!(login(&pool, &config, &mock_login).await, user_id);
+    }
+
+    #[sqlx::test(migrator = "DB_MIGRATOR")]
+    async fn oidc_login_links_user_by_email(pool: PgPool) {
+        let existing_user_id: Uuid = sqlx::query_scalar(
+            "insert into app_user (email, scopes) values ('kurt@lakers.com', '{}') returning id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let mock_login: MockState = Arc::new(Mutex::new(MockLogin {
+            subject: String::from("rambo"),
+            email: Some(String::from("kurt@lakers.com")),
+            ..Default::default()
+        }));
+        let config: OidcConfig = start_mock_provider(mock_login.clone()).await;
+
+        assert_eq!(login(&pool, &config, &mock_login).await, existing_user_id);
+    }
+
+    #[test]
+    fn oidc_validate_claims() {
+        let claims = IdTokenClaims {
+            iss: String::from("https://idp.example.com"),
+            sub: String::from("rambo"),
+            aud: Audience::Many(vec![String::from("hello-world")]),
+            exp: 100,
+            nonce: Some(String::from("nonce")),
+            email: None,
+            email_verified: None,
+            name: None,
+        };
+
+        assert!(validate_claims(
+            &claims,
+            "https://idp.example.com",
+            "hello-world",
+            "nonce",
+            50
+        )
+        .is_ok());
+        assert!(validate_claims(
+            &claims,
+            "https://evil.example.com",
+            "hello-world",
+            "nonce",
+            50
+        )
+        .is_err());
+        assert!(
+            validate_claims(&claims, "https://idp.example.com", "other-app", "nonce", 50).is_err()
+        );
+        assert!(validate_claims(
+            &claims,
+            "https://idp.example.com",
+            "hello-world",
+            "other",
+            50
+        )
+        .is_err());
+        assert!(validate_claims(
+            &claims,
+            "https://idp.example.com",
+            "hello-world",
+            "nonce",
+            100
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn oidc_validate_endpoint() {
+        assert!(validate_endpoint("https://idp.example.com/token").is_ok());
+        assert!(validate_endpoint("http://127.0.0.1:8080/token").is_ok());
+        assert!(validate_endpoint("http://idp.example.com/token").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+//! Provides login sessions for users, tracked with a cookie holding a random token. Like API keys, only a hash of the
+//! token is stored.
+//!
+//! The cookie is `HttpOnly` so scripts can't read it, and `SameSite=Lax` so browsers don't send it on cross-site
+//! `PUT`/`POST` requests, which protects our state changing APIs from cross-site request forgery.
+//!
+//! A login in progress is tied to the browser that started it with another short-lived cookie holding its `state`
+//! (see [`oidc`](super::oidc)), so a callback URL from someone else's login can't log the browser in as them.
+use std::time::Duration;
+
+use axum::http::{header, HeaderMap};
+use sqlx::{Pool, Postgres};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::services::db;
+
+use super::{api_keys::to_scopes, generate_token, hash_token, Principal, Subject};
+
+/// Name of the cookie holding the session token
+pub const SESSION_COOKIE: &str = "px_session";
+/// Name of the cookie holding the `state` of the login the browser started
+pub const LOGIN_STATE_COOKIE: &str = "px_login_state";
+
+/// Starts a new session for the user that lasts for the max age (i.e. the `auth_session_max_age` config value),
+/// returning the token to send back in the session cookie
+pub async fn create_session(
+    db_pool: &Pool<Postgres>,
+    user_id: Uuid,
+    max_age: Duration,
+) -> Result<String, sqlx::Error> {
+    let token: String = generate_token(32);
+
+    // Clean up sessions that have expired, then start this one
+    sqlx::query!("delete from user_session where expires_at < now()")
+        .execute(db_pool)
+        .instrument(db::query_span("DELETE user_session"))
+        .await?;
+    sqlx::query!(
+        r#"INSERT INTO user_session
+        (token_hash, user_id, expires_at)
+        VALUES ($1, $2, now() + make_interval(secs => $3))"#,
+        hash_token(&token),
+        user_id,
+        max_age.as_secs_f64()
+    )
+    .execute(db_pool)
+    .instrument(db::query_span("INSERT user_session"))
+    .await?;
+
+    Ok(token)
+}
+
+/// Looks up the [`Principal`] for a session token. Returns None if the session doesn't exist or has expired.
+pub async fn authenticate(
+    db_pool: &Pool<Postgres>,
+    token: &str,
+) -> Result<Option<Principal>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"select app_user.id, app_user.scopes
+        from user_session join app_user on app_user.id = user_session.user_id
+        where user_session.token_hash = $1 and user_session.expires_at > now()"#,
+        hash_token(token)
+    )
+    .fetch_optional(db_pool)
+    .instrument(db::query_span("SELECT user_session"))
+    .await?;
+
+    Ok(row.map(|row| Principal {
+        subject: Subject::User(row.id),
+        scopes: to_scopes(&row.scopes),
+    }))
+}
+
+/// Ends a session, i.e. logs the user out
+pub async fn delete_session(db_pool: &Pool<Postgres>, token: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "delete from user_session where token_hash = $1",
+        hash_token(token)
+    )
+    .execute(db_pool)
+    .instrument(db::query_span("DELETE user_session"))
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the session token from the request's cookies, if there is one
+pub fn get_session_token(headers: &HeaderMap) -> Option<&str> {
+    get_cookie(headers, SESSION_COOKIE)
+}
+
+/// Returns the `state` of the login the browser started from the request's cookies, if there is one
+pub fn get_login_state(headers: &HeaderMap) -> Option<&str> {
+    get_cookie(headers, LOGIN_STATE_COOKIE)
+}
+
+fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+/// Builds the `Set-Cookie` header value for a new session. Only mark the cookie `Secure` when served over HTTPS, or
+/// browsers won't send it back.
+pub fn build_session_cookie(token: &str, max_age: Duration, secure: bool) -> String {
+    build_cookie(SESSION_COOKIE, token, max_age.as_secs(), secure)
+}
+
+/// Builds the `Set-Cookie` header value that removes the session cookie from the browser
+pub fn build_expired_session_cookie(secure: bool) -> String {
+    build_cookie(SESSION_COOKIE, "", 0, secure)
+}
+
+/// Builds the `Set-Cookie` header value for the `state` of the login the browser is starting, which lasts as long as
+/// the user has to complete the login
+pub fn build_login_state_cookie(state: &str, max_age: Duration, secure: bool) -> String {
+    build_cookie(LOGIN_STATE_COOKIE, state, max_age.as_secs(), secure)
+}
+
+/// Builds the `Set-Cookie` header value that removes the login's `state` from the browser, once it's been completed
+pub fn build_expired_login_state_cookie(secure: bool) -> String {
+    build_cookie(LOGIN_STATE_COOKIE, "", 0, secure)
+}
+
+fn build_cookie(name: &str, value: &str, max_age: u64, secure: bool) -> String {
+    let mut cookie: String =
+        format!("{name}={value}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax");
+    if secure {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{services::auth::Scope, DB_MIGRATOR};
+    use axum::http::HeaderValue;
+    use pretty_assertions::assert_eq;
+    use sqlx::PgPool;
+
+    #[test]
+    fn sessions_get_session_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(get_session_token(&headers), None);
+
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; px_session=abc123; px_login_state=xyz"),
+        );
+        assert_eq!(get_session_token(&headers), Some("abc123"));
+        assert_eq!(get_login_state(&headers), Some("xyz"));
+    }
+
+    /// Validates the full lifecycle of a session: create, use, delete
+    #[sqlx::test(migrator = "DB_MIGRATOR")]
+    async fn sessions_authenticate(pool: PgPool) {
+        let user_id: Uuid = sqlx::query_scalar(
+            "insert into app_user (email, scopes) values ('kurt@lakers.com', '{players:read}') returning id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let token: String = create_session(&pool, user_id, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let principal: Principal = authenticate(&pool, &token).await.unwrap().unwrap();
+        assert_eq!(principal.subject, Subject::User(user_id));
+        assert_eq!(principal.scopes, vec![Scope::PlayersRead]);
+
+        delete_session(&pool, &token).await.unwrap();
+        assert!(authenticate(&pool, &token).await.unwrap().is_none());
+
+        // Expired sessions are cleaned up when the next one starts
+        create_session(&pool, user_id, Duration::ZERO)
+            .await
+            .unwrap();
+        create_session(&pool, user_id, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let sessions: i64 = sqlx::query_scalar("select count(*) from user_session")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sessions, 1);
+    }
+}
@@ -1,29 +1,354 @@
-//! General purpose set of utililties for interacting with configuration values for in the .cargo/[*]config.toml files.
-
-use std::env;
-
-/// Loads a enviornemnt variable in the root .env file, validates its required exsistence,
-/// and converts it to a number. Panics if not found or not an integer.
-pub fn get_env_var_as_number_or_panic(var_name: &str) -> u32 {
-    get_env_var_or_panic(var_name)
-        .parse::<u32>()
-        .unwrap_or_else(|_| {
-            panic!(
-                "{} {}",
-                var_name, "must be an integer in .cargo/config.toml file."
-            )
-        })
+//! Provides our app's configuration as a typed [`AppConfig`], which is loaded and validated once at startup, then
+//! passed to whatever needs it (e.g. through [`AppState`](super::app_server::AppState)).
+//!
+//! Values are layered from the following sources, later ones overriding earlier ones:
+//!
+//! 1. Defaults, from config/default.toml (compiled into the app)
+//! 2. An optional TOML config file, whose path is set in the `app_config_file` environment variable
+//! 3. Environment variables, which includes the [env] section of the .cargo/[*]config.toml files when run with Cargo
+//!
+//! A setting has the same name in every source, e.g. `app_server_url`. Loading reports every missing or invalid setting
+//! at once, rather than panicking on the first one whenever its code path first runs.
+//!
+//! Secrets (e.g. `database_password`) can instead be read from a file, whose path is set in the secret's name with a
+//! `_file` suffix (e.g. `DATABASE_PASSWORD_FILE=/run/secrets/db_password`). This is how Docker and Kubernetes mount
+//! secrets, so they needn't be put in environment variables. Secrets are held as a [`Secret`], so they're redacted
+//! wherever they're formatted.
+pub mod secret;
+
+use std::{env, fmt, fs, str::FromStr, time::Duration};
+
+use axum::http::Uri;
+use config::{builder::DefaultState, Config, ConfigBuilder, Environment, File, FileFormat};
+use secret::Secret;
+use serde::de::DeserializeOwned;
+use tracing_subscriber::EnvFilter;
+
+use super::{
+    app_server::{listener::UnixSocketConfig, serve::ConnectionConfig, tls::TlsConfig},
+    auth::{self, oidc::OidcConfig, Scope},
+    cors::CorsConfig,
+    db,
+    rate_limit::RateLimitConfig,
+    security_headers::SecurityHeaders,
+    spa::dev_proxy,
+    static_mounts::StaticMount,
+    timeouts::RequestTimeouts,
+    tracing::logs::{LogFileConfig, LogOutput},
+};
+
+/// Our defaults for every environment. Settings without a default (e.g. `database_host`) must be set in another source.
+const DEFAULTS: &str = include_str!("../../config/default.toml");
+
+/// Environment variable holding the path of the optional config file
+const CONFIG_FILE_VAR: &str = "app_config_file";
+
+/// Every setting our app uses. See config/default.toml for what each one does.
+#[derive(Clone)]
+pub struct AppConfig {
+    // app server
+    pub app_server_url: String,
+    /// Also serves on this Unix domain socket when set
+    pub app_server_unix_socket: Option<UnixSocketConfig>,
+    pub app_server_connections: ConnectionConfig,
+    pub request_timeouts: RequestTimeouts,
+    pub app_server_shutdown_drain_delay: Duration,
+    pub health_check_timeout: Duration,
+    /// None serves health probes, metrics and admin APIs on the app server
+    pub admin_server_url: Option<String>,
+    pub spa_dist_dir: String,
+    pub spa_fallback_url: String,
+    /// Writes missing .br and .gz copies of the SPA's files at startup
+    pub spa_precompress_files: bool,
+    /// Given to the SPA at runtime, empty for the origin it's served from
+    pub spa_api_base_url: String,
+    /// Given to the SPA at runtime
+    pub spa_features: Vec<String>,
+    /// Dev mode, which proxies the SPA to its dev server at this URL rather than serving its build
+    pub spa_dev_server_url: Option<Uri>,
+    /// Files and directories served next to the SPA
+    pub static_mounts: Vec<StaticMount>,
+    /// None serves plain HTTP
+    pub tls: Option<TlsConfig>,
+
+    // db
+    pub database_user: String,
+    pub database_password: Secret,
+    pub database_host: String,
+    pub database_port: u16,
+    pub database_name: String,
+    pub database_max_connections: u32,
+    pub database_connection_aquire_timeout: Duration,
+
+    // search
+    pub search_server_url: String,
+    pub search_master_key: Secret,
+    pub player_search_index: String,
+
+    // tracing and logging
+    /// None turns trace export off
+    pub otel_exporter_otlp_endpoint: Option<String>,
+    pub otel_service_name: String,
+    pub log_stdout: LogOutput,
+    /// None turns log files off
+    pub log_file: Option<LogFileConfig>,
+
+    // auth
+    pub auth_anonymous_scopes: Vec<Scope>,
+    pub auth_default_user_scopes: Vec<Scope>,
+    pub auth_session_max_age: Duration,
+    /// None turns single sign-on off
+    pub oidc: Option<OidcConfig>,
+
+    // policies for requests and responses
+    pub rate_limit: RateLimitConfig,
+    /// None turns CORS off
+    pub cors: Option<CorsConfig>,
+    pub security_headers: SecurityHeaders,
+}
+
+impl AppConfig {
+    /// Loads the config from all our sources, see the module docs
+    pub fn load() -> Result<Self, ConfigError> {
+        let source: Config = build_sources()
+            .build()
+            .map_err(|err| ConfigError(vec![err.to_string()]))?;
+        AppConfig::from_source(&source)
+    }
+
+    /// Reads and validates every setting from the source, returning all the problems found
+    pub fn from_source(source: &Config) -> Result<Self, ConfigError> {
+        let mut reader = ConfigReader::new(source);
+        let database_host: (String, u16) = reader.get_with("database_host", db::parse_host);
+
+        let app_config = AppConfig {
+            app_server_url: reader.get_string("app_server_url"),
+            app_server_unix_socket: UnixSocketConfig::from_config(&mut reader),
+            app_server_connections: ConnectionConfig::from_config(&mut reader),
+            request_timeouts: RequestTimeouts::from_config(&mut reader),
+            app_server_shutdown_drain_delay: reader.get_seconds("app_server_shutdown_drain_delay"),
+            health_check_timeout: reader.get_seconds("health_check_timeout"),
+            admin_server_url: reader.get_optional_string("admin_server_url"),
+            spa_dist_dir: reader.get_string("spa_dist_dir"),
+            spa_fallback_url: reader.get_string("spa_fallback_url"),
+            spa_precompress_files: reader.get("spa_precompress_files"),
+            spa_api_base_url: reader.get_string("spa_api_base_url"),
+            spa_features: reader.get_list("spa_features"),
+            spa_dev_server_url: dev_proxy::dev_server_url_from_config(&mut reader),
+            static_mounts: StaticMount::from_config(&mut reader),
+            tls: TlsConfig::from_config(&mut reader),
+
+            database_user: reader.get_string("database_user"),
+            database_password: reader.get_secret("database_password"),
+            database_host: database_host.0,
+            database_port: database_host.1,
+            database_name: reader.get_string("database_name"),
+            database_max_connections: reader.get("database_max_connections"),
+            database_connection_aquire_timeout: reader
+                .get_seconds("database_connection_aquire_timeout"),
+
+            search_server_url: reader.get_string("search_server_url"),
+            search_master_key: reader.get_secret("search_master_key"),
+            player_search_index: reader.get_string("player_search_index"),
+
+            otel_exporter_otlp_endpoint: reader.get_optional_string("otel_exporter_otlp_endpoint"),
+            otel_service_name: reader.get_string("otel_service_name"),
+            log_stdout: LogOutput::from_config(&mut reader, "log_stdout"),
+            log_file: LogFileConfig::from_config(&mut reader),
+
+            auth_anonymous_scopes: reader.get_with("auth_anonymous_scopes", auth::parse_scopes),
+            auth_default_user_scopes: reader
+                .get_with("auth_default_user_scopes", auth::parse_scopes),
+            auth_session_max_age: reader.get_seconds("auth_session_max_age"),
+            oidc: OidcConfig::from_config(&mut reader),
+
+            rate_limit: RateLimitConfig::from_config(&mut reader),
+            cors: CorsConfig::from_config(&mut reader),
+            security_headers: SecurityHeaders::from_config(&mut reader),
+        };
+
+        match reader.problems.is_empty() {
+            true => Ok(app_config),
+            false => Err(ConfigError(reader.problems)),
+        }
+    }
+}
+
+/// Returns our sources in order, so callers (e.g. tests) can add overrides on top of them
+pub fn build_sources() -> ConfigBuilder<DefaultState> {
+    let mut builder: ConfigBuilder<DefaultState> =
+        Config::builder().add_source(File::from_str(DEFAULTS, FileFormat::Toml));
+
+    if let Some(path) = env::var(CONFIG_FILE_VAR)
+        .ok()
+        .filter(|path| !path.trim().is_empty())
+    {
+        builder = builder.add_source(File::new(&path, FileFormat::Toml));
+    }
+
+    builder.add_source(Environment::default())
+}
+
+/// Every problem found while loading the config
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
 }
 
-/// Loads a enviornemnt variable in the root .env file and validates its required exsistence.
-/// Panics if not found or not an integer.
-pub fn get_env_var_or_panic(var_name: &str) -> String {
-    env::var(var_name).unwrap_or_else(|_| {
-        panic!(
-            "{} {} {}",
-            "Missing", var_name, "in .cargo/config.toml file."
-        )
-    })
+/// Reads settings from a config source, noting every problem instead of stopping at the first one. Each getter
+/// returns a default value for a setting with a problem, which is never used since loading then fails.
+pub struct ConfigReader<'a> {
+    source: &'a Config,
+    problems: Vec<String>,
+}
+
+impl<'a> ConfigReader<'a> {
+    pub fn new(source: &'a Config) -> Self {
+        ConfigReader {
+            source,
+            problems: Vec::new(),
+        }
+    }
+
+    /// Notes a problem with a setting
+    pub fn add_problem(&mut self, key: &str, problem: impl fmt::Display) {
+        self.problems.push(format!("{key}: {problem}"));
+    }
+
+    /// Reads a required setting
+    pub fn get_string(&mut self, key: &str) -> String {
+        self.get_required(key).unwrap_or_default()
+    }
+
+    fn get_required(&mut self, key: &str) -> Option<String> {
+        match self.source.get_string(key) {
+            Ok(value) => Some(value),
+            Err(config::ConfigError::NotFound(_)) => {
+                self.add_problem(key, "is missing");
+                None
+            }
+            Err(err) => {
+                self.add_problem(key, err);
+                None
+            }
+        }
+    }
+
+    /// Reads a setting that turns an optional feature on, returning None if it's missing or empty
+    pub fn get_optional_string(&mut self, key: &str) -> Option<String> {
+        match self.source.get_string(key) {
+            Ok(value) => Some(value).filter(|value| !value.trim().is_empty()),
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(err) => {
+                self.add_problem(key, err);
+                None
+            }
+        }
+    }
+
+    /// Reads a required secret, from either the setting or the file at the path in its [key]_file setting
+    pub fn get_secret(&mut self, key: &str) -> Secret {
+        Secret::from(match self.get_secret_file(key) {
+            Some(secret) => secret,
+            None => self.get_string(key),
+        })
+    }
+
+    /// Reads a secret that turns an optional feature on, from either the setting or the file at the path in its
+    /// [key]_file setting. Returns None if neither is set.
+    pub fn get_optional_secret(&mut self, key: &str) -> Option<Secret> {
+        match self.get_secret_file(key) {
+            Some(secret) => Some(secret),
+            None => self.get_optional_string(key),
+        }
+        .map(Secret::from)
+    }
+
+    /// Reads a secret from the file at the path in its [key]_file setting, returning None if that isn't set
+    fn get_secret_file(&mut self, key: &str) -> Option<String> {
+        let file_key: String = format!("{key}_file");
+        let path: String = self.get_optional_string(&file_key)?;
+
+        // Like Docker's images, don't guess which of the two was meant
+        if self.get_optional_string(key).is_some() {
+            self.add_problem(key, format!("can't be set along with {file_key}"));
+        }
+
+        match fs::read_to_string(&path) {
+            // Files often end with a newline that isn't part of the secret
+            Ok(secret) => Some(secret.trim_end_matches(['\r', '\n']).to_string()),
+            Err(err) => {
+                self.add_problem(&file_key, format!("\"{path}\" can't be read, {err}"));
+                Some(String::new())
+            }
+        }
+    }
+
+    /// Reads a required setting and parses it, e.g. as a number or enum
+    pub fn get<T>(&mut self, key: &str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: fmt::Display,
+    {
+        self.get_with(key, str::parse)
+    }
+
+    /// Reads a required setting and parses it with the function
+    pub fn get_with<T, E>(&mut self, key: &str, parse: impl FnOnce(&str) -> Result<T, E>) -> T
+    where
+        T: Default,
+        E: fmt::Display,
+    {
+        let Some(value) = self.get_required(key) else {
+            return T::default();
+        };
+        parse(value.trim()).unwrap_or_else(|err| {
+            self.add_problem(key, format!("\"{value}\" is invalid, {err}"));
+            T::default()
+        })
+    }
+
+    /// Reads a required setting that's a number of seconds
+    pub fn get_seconds(&mut self, key: &str) -> Duration {
+        Duration::from_secs(self.get(key))
+    }
+
+    /// Reads a required setting that's a comma separated list, e.g. "GET, POST". An empty string is an empty list.
+    pub fn get_list(&mut self, key: &str) -> Vec<String> {
+        self.get_string(key)
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// Reads a setting that's a list of tables, e.g. `[{ path = "/docs" }]`. A missing setting is an empty list.
+    pub fn get_table_list<T: DeserializeOwned>(&mut self, key: &str) -> Vec<T> {
+        match self.source.get::<Vec<T>>(key) {
+            Ok(values) => values,
+            Err(config::ConfigError::NotFound(_)) => Vec::new(),
+            Err(err) => {
+                self.add_problem(key, err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Reads a required setting that's a log filter, in the same directive syntax as `RUST_LOG`
+    pub fn get_log_filter(&mut self, key: &str) -> String {
+        self.get_with(key, |value| {
+            EnvFilter::try_new(value).map(|_| value.to_string())
+        })
+    }
 }
 
 #[cfg(test)]
@@ -32,14 +357,102 @@ mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
 
+    fn load_with_overrides(overrides: &[(&str, &str)]) -> Result<AppConfig, ConfigError> {
+        let mut builder = build_sources();
+        for (key, value) in overrides {
+            builder = builder.set_override(*key, *value).unwrap();
+        }
+        AppConfig::from_source(&builder.build().unwrap())
+    }
+
     #[test]
-    #[should_panic(expected = "Missing BLAH in .cargo/config.toml file.")]
-    fn configs_validate_get_env_var_or_panic_panics() {
-        get_env_var_or_panic("BLAH");
+    fn configs_load() {
+        let app_config: AppConfig = load_with_overrides(&[("admin_server_url", "")]).unwrap();
+        // From the defaults, since it isn't set in .cargo/config.toml
+        assert_eq!(app_config.database_max_connections, 5);
+        assert_eq!(app_config.health_check_timeout, Duration::from_secs(2));
+        // From the environment
+        assert_eq!(app_config.database_name, env::var("database_name").unwrap());
+        assert!(app_config.admin_server_url.is_none());
     }
 
     #[test]
-    fn configs_validate_get_env_var_or_panic() {
-        assert_eq!("postgres", get_env_var_or_panic("database_user"));
+    fn configs_report_every_problem() {
+        let err: ConfigError = load_with_overrides(&[
+            ("database_max_connections", "lots"),
+            ("log_stdout_filter", "info,sqlx=loud"),
+            ("auth_anonymous_scopes", "players:delete"),
+        ])
+        .err()
+        .unwrap();
+
+        let keys: Vec<&str> = err
+            .0
+            .iter()
+            .map(|problem| problem.split(':').next().unwrap())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "database_max_connections",
+                "log_stdout_filter",
+                "auth_anonymous_scopes"
+            ]
+        );
+    }
+
+    #[test]
+    fn configs_missing_setting() {
+        let source: Config = Config::builder()
+            .add_source(File::from_str(DEFAULTS, FileFormat::Toml))
+            .build()
+            .unwrap();
+        let err: ConfigError = AppConfig::from_source(&source).err().unwrap();
+        // No defaults for where our services are
+        assert!(err
+            .0
+            .contains(&String::from("database_password: is missing")));
+        assert!(err
+            .0
+            .contains(&String::from("search_master_key: is missing")));
+    }
+
+    #[test]
+    fn configs_secret_file() {
+        let path = env::temp_dir().join(format!("configs_secret_file_{}", uuid::Uuid::new_v4()));
+        fs::write(&path, "s3cret\n").unwrap();
+        let path: &str = path.to_str().unwrap();
+
+        let source: Config = Config::builder()
+            .set_override("search_master_key_file", path)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut reader = ConfigReader::new(&source);
+        assert_eq!(reader.get_secret("search_master_key").expose(), "s3cret");
+        assert_eq!(reader.get_optional_secret("oidc_client_secret"), None);
+        assert!(reader.problems.is_empty());
+
+        // Set both ways, or set to a file that doesn't exist
+        let source: Config = Config::builder()
+            .set_override("search_master_key", "master-key")
+            .unwrap()
+            .set_override("search_master_key_file", path)
+            .unwrap()
+            .set_override("database_password_file", "/does/not/exist")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut reader = ConfigReader::new(&source);
+        reader.get_secret("search_master_key");
+        reader.get_secret("database_password");
+        let keys: Vec<&str> = reader
+            .problems
+            .iter()
+            .map(|problem| problem.split(':').next().unwrap())
+            .collect();
+        assert_eq!(keys, vec!["search_master_key", "database_password_file"]);
+
+        fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,54 @@
+//! Provides [`Secret`], which holds a credential (e.g. a password or API key) so it can't end up in logs by accident.
+use std::fmt;
+
+/// What a secret's value is replaced with when it's formatted
+pub const REDACTED: &str = "[REDACTED]";
+
+/// A credential whose `Debug` and `Display` output is redacted. Its value is only available through
+/// [`Secret::expose`], so every place that uses it is easy to find.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    /// Returns the secret's value. Only pass it to what needs the credential, never to a log.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({REDACTED})")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn secret_is_redacted() {
+        let secret = Secret::new("lakers");
+        assert_eq!(format!("{secret}"), "[REDACTED]");
+        assert_eq!(format!("{secret:?}"), "Secret([REDACTED])");
+        assert_eq!(secret.expose(), "lakers");
+    }
+}
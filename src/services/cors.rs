@@ -0,0 +1,212 @@
+//! Provides the Cross-Origin Resource Sharing (CORS) policy for our APIs, for when the SPA is hosted on a different
+//! origin than this app server and calls the APIs cross-origin.
+//!
+//! CORS is turned on by listing the allowed origins in the `cors_allowed_origins` config value, and only applies to
+//! `/api` routes. The SPA's static files are always served same-origin.
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use super::configs::ConfigReader;
+
+/// Value that allows any origin, method or header
+const ANY: &str = "*";
+
+/// The CORS policy for our APIs, as set in the cors_* config values
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsConfig {
+    /// Origins (e.g. "https://app.example.com") allowed to call our APIs, or ["*"] for any origin
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed on cross-origin requests, or ["*"] for any method
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed on cross-origin requests, or ["*"] for any header
+    pub allowed_headers: Vec<String>,
+    /// Whether browsers may send cookies (i.e. the session cookie) on cross-origin requests
+    pub allow_credentials: bool,
+    /// How long browsers may cache the result of a preflight request
+    pub max_age: Duration,
+}
+
+impl CorsConfig {
+    /// Reads the CORS policy from the cors_* config values. Returns None if CORS is turned off, i.e.
+    /// `cors_allowed_origins` is empty.
+    pub fn from_config(reader: &mut ConfigReader) -> Option<Self> {
+        let allowed_origins: Vec<String> = reader.get_list("cors_allowed_origins");
+        if allowed_origins.is_empty() {
+            return None;
+        }
+
+        let config = CorsConfig {
+            allowed_origins,
+            allowed_methods: reader.get_list("cors_allowed_methods"),
+            allowed_headers: reader.get_list("cors_allowed_headers"),
+            allow_credentials: reader.get("cors_allow_credentials"),
+            max_age: reader.get_seconds("cors_max_age"),
+        };
+        // So an invalid policy is reported along with every other config problem
+        if let Err(err) = config.clone().try_into_layer() {
+            reader.add_problem("cors", err);
+        }
+        Some(config)
+    }
+
+    /// Builds the layer that applies the policy. Panics if the policy is invalid, which is validated when our config
+    /// is loaded.
+    pub fn into_layer(self) -> CorsLayer {
+        self.try_into_layer().unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Builds the layer that applies the policy, or returns why the policy is invalid
+    pub fn try_into_layer(self) -> Result<CorsLayer, String> {
+        // Browsers refuse credentialed responses that allow everything with "*", and tower-http would panic on the
+        // first request, so fail with a clearer message here
+        if self.allow_credentials
+            && [
+                &self.allowed_origins,
+                &self.allowed_methods,
+                &self.allowed_headers,
+            ]
+            .iter()
+            .any(|values| is_any(values))
+        {
+            return Err(String::from(
+                "cors_allow_credentials can't be true when a cors_allowed_* value is \"*\"",
+            ));
+        }
+
+        let allowed_origins: AllowOrigin = if is_any(&self.allowed_origins) {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(
+                self.allowed_origins
+                    .iter()
+                    .map(|origin| {
+                        HeaderValue::from_str(origin.trim_end_matches('/')).map_err(|_| {
+                            format!("cors_allowed_origins has an invalid origin \"{origin}\"")
+                        })
+                    })
+                    .collect::<Result<Vec<HeaderValue>, String>>()?,
+            )
+        };
+
+        let allowed_methods: AllowMethods = if is_any(&self.allowed_methods) {
+            AllowMethods::any()
+        } else {
+            AllowMethods::list(
+                self.allowed_methods
+                    .iter()
+                    .map(|method| {
+                        method.to_uppercase().parse::<Method>().map_err(|_| {
+                            format!("cors_allowed_methods has an invalid method \"{method}\"")
+                        })
+                    })
+                    .collect::<Result<Vec<Method>, String>>()?,
+            )
+        };
+
+        let allowed_headers: AllowHeaders = if is_any(&self.allowed_headers) {
+            AllowHeaders::any()
+        } else {
+            AllowHeaders::list(
+                self.allowed_headers
+                    .iter()
+                    .map(|header| {
+                        header.parse::<HeaderName>().map_err(|_| {
+                            format!("cors_allowed_headers has an invalid header \"{header}\"")
+                        })
+                    })
+                    .collect::<Result<Vec<HeaderName>, String>>()?,
+            )
+        };
+
+        Ok(CorsLayer::new()
+            .allow_origin(allowed_origins)
+            .allow_methods(allowed_methods)
+            .allow_headers(allowed_headers)
+            .allow_credentials(self.allow_credentials)
+            .max_age(self.max_age))
+    }
+}
+
+fn is_any(values: &[String]) -> bool {
+    values.iter().any(|value| value == ANY)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use axum::{
+        http::{header, StatusCode},
+        routing::get,
+        Router,
+    };
+    use axum_test::TestServer;
+    use pretty_assertions::assert_eq;
+
+    fn split_list(value: &str) -> Vec<String> {
+        value
+            .split(',')
+            .map(|value| value.trim().to_string())
+            .collect()
+    }
+
+    fn build_config(allowed_origins: &str) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: split_list(allowed_origins),
+            allowed_methods: split_list("GET, PUT"),
+            allowed_headers: split_list("authorization, content-type"),
+            allow_credentials: true,
+            max_age: Duration::from_secs(600),
+        }
+    }
+
+    #[tokio::test]
+    async fn cors_preflight() {
+        let router: Router = Router::new()
+            .route("/api/test", get(|| async { "ok" }))
+            .layer(build_config("https://app.example.com/").into_layer());
+        let server = TestServer::new(router).unwrap();
+
+        let response = server
+            .method(Method::OPTIONS, "/api/test")
+            .add_header(
+                header::ORIGIN,
+                HeaderValue::from_static("https://app.example.com"),
+            )
+            .add_header(
+                header::ACCESS_CONTROL_REQUEST_METHOD,
+                HeaderValue::from_static("PUT"),
+            )
+            .await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(
+            response.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "https://app.example.com"
+        );
+        assert_eq!(
+            response.header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            "true"
+        );
+        assert_eq!(response.header(header::ACCESS_CONTROL_MAX_AGE), "600");
+
+        // Origins that aren't allowed don't get any CORS headers, so browsers block the response
+        let response = server
+            .get("/api/test")
+            .add_header(
+                header::ORIGIN,
+                HeaderValue::from_static("https://evil.example.com"),
+            )
+            .await;
+        assert!(response
+            .maybe_header(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "cors_allow_credentials")]
+    fn cors_credentials_with_any_origin() {
+        let _ = build_config("*").into_layer();
+    }
+}
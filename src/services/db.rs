@@ -1,26 +1,23 @@
 //! Provides utilities to initialize usage of the database and provide functions to interact with it.
-use std::time::Duration;
-
 use colored::Colorize;
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Pool, Postgres,
+};
 
-use crate::services::configs;
+use crate::services::configs::AppConfig;
 
 /// Initalize a DB Conn Pool with with the following features:
 ///  
-/// 1. Pool size of number of connections as defined by DATABASE_MAX_CONNECTIONS in .env file
+/// 1. Pool size of number of connections as defined by DATABASE_MAX_CONNECTIONS in our config
 /// 2. Connection acquire timeout
-/// 3. Prints out the connection string (with password redacted)
+/// 3. Prints out where it's connecting to (without the password)
 ///
-pub async fn init_db_conn_pool() -> Result<Pool<Postgres>, sqlx::Error> {
+pub async fn init_db_conn_pool(app_config: &AppConfig) -> Result<Pool<Postgres>, sqlx::Error> {
     let pool: Pool<Postgres> = PgPoolOptions::new()
-        .max_connections(configs::get_env_var_as_number_or_panic(
-            "database_max_connections",
-        ))
-        .acquire_timeout(Duration::from_secs(u64::from(
-            configs::get_env_var_as_number_or_panic("database_connection_aquire_timeout"),
-        )))
-        .connect(get_db_connect_string().as_str())
+        .max_connections(app_config.database_max_connections)
+        .acquire_timeout(app_config.database_connection_aquire_timeout)
+        .connect_with(build_connect_options(app_config))
         .await?;
 
     let row: (i64,) = sqlx::query_as("SELECT $1")
@@ -38,10 +35,69 @@ pub async fn init_db_conn_pool() -> Result<Pool<Postgres>, sqlx::Error> {
     Ok(pool)
 }
 
-// Return the DB connect string from the .env file, priting out the string
-fn get_db_connect_string() -> String {
-    let db_connect_string: String = configs::get_env_var_or_panic("DATABASE_URL");
-    tracing::debug!("DB Connect str: {0}", db_connect_string);
+/// Creates a span for a DB query, so it's exported as its own span when trace export is on. Instrument queries with:
+/// `.instrument(db::query_span("SELECT player"))`
+pub fn query_span(name: &'static str) -> tracing::Span {
+    tracing::info_span!(
+        "db.query",
+        otel.name = name,
+        otel.kind = "client",
+        db.system = "postgresql"
+    )
+}
+
+/// Returns the DB connection options from the database_* settings in our config, printing out where they connect to
+pub fn build_connect_options(app_config: &AppConfig) -> PgConnectOptions {
+    tracing::debug!(
+        "DB Connect str: postgresql://{}@{}:{}/{}",
+        app_config.database_user,
+        app_config.database_host,
+        app_config.database_port,
+        app_config.database_name
+    );
+
+    PgConnectOptions::new()
+        .host(&app_config.database_host)
+        .port(app_config.database_port)
+        .username(&app_config.database_user)
+        .password(app_config.database_password.expose())
+        .database(&app_config.database_name)
+}
+
+/// Parses a `database_host` config value of the form "host[:port]", where the port defaults to Postgres' 5432
+pub fn parse_host(value: &str) -> Result<(String, u16), String> {
+    let (host, port) = match value.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse::<u16>()
+                .map_err(|_| format!("port \"{port}\" must be a number"))?,
+        ),
+        None => (value, 5432),
+    };
+
+    match host.is_empty() {
+        true => Err(String::from("host must not be empty")),
+        false => Ok((host.to_string(), port)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use pretty_assertions::assert_eq;
 
-    db_connect_string
+    #[test]
+    fn db_parse_host() {
+        assert_eq!(
+            parse_host("localhost:5433").unwrap(),
+            (String::from("localhost"), 5433)
+        );
+        assert_eq!(
+            parse_host("db.internal").unwrap(),
+            (String::from("db.internal"), 5432)
+        );
+        assert!(parse_host("localhost:postgres").is_err());
+        assert!(parse_host(":5432").is_err());
+    }
 }
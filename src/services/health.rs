@@ -0,0 +1,181 @@
+//! Provides the health probes our orchestrator and load balancers use.
+//!
+//! - `/healthz` is the liveness probe, which only tells the process is up and serving requests.
+//! - `/readyz` is the readiness probe, which checks every dependency we need to serve traffic (the database, Search and
+//!   the SPA's files, unless they're embedded in the binary), each with its own timeout, and reports a breakdown of the checks.
+//!
+//! Once the app server is told to shut down, `/readyz` reports not ready right away, so load balancers drain traffic
+//! away before the server stops accepting connections (see `app_server_shutdown_drain_delay`).
+use std::{
+    future::Future,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+
+use crate::{
+    api::resources::{DependencyCheck, ReadinessReport},
+    services::app_server::AppState,
+};
+
+pub const LIVENESS_PATH: &str = "/healthz";
+pub const READINESS_PATH: &str = "/readyz";
+
+/// Set once the app server is told to shut down
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Returns the routes for our health probes. They aren't under `/api`, so they don't need credentials and aren't rate
+/// limited.
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(LIVENESS_PATH, get(liveness))
+        .route(READINESS_PATH, get(readiness))
+}
+
+/// Makes the readiness probe report not ready from now on
+pub fn mark_shutting_down() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+async fn liveness() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+async fn readiness(State(app_state): State<AppState>) -> impl IntoResponse {
+    let report: ReadinessReport = check_readiness(
+        &app_state,
+        &app_state.config.spa_dist_dir,
+        app_state.config.health_check_timeout,
+    )
+    .await;
+
+    let status: StatusCode = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Checks all our dependencies at the same time, so the probe takes at most one timeout
+async fn check_readiness(
+    app_state: &AppState,
+    spa_dist_dir: &str,
+    timeout: Duration,
+) -> ReadinessReport {
+    let (database, search, spa) = tokio::join!(
+        check("database", timeout, async {
+            // Goes through the pool, so this also fails when no connection can be acquired
+            sqlx::query("SELECT 1")
+                .execute(&app_state.db_pool)
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        }),
+        check("search", timeout, async {
+            let health = app_state
+                .search_client
+                .health()
+                .await
+                .map_err(|err| err.to_string())?;
+            match health.status.as_str() {
+                "available" => Ok(()),
+                status => Err(format!("Search status is \"{status}\"")),
+            }
+        }),
+        check("spa_dist_dir", timeout, async {
+            // An SPA embedded in the binary is always there, and its dev server serves it in dev mode
+            if cfg!(feature = "embed-spa") || app_state.config.spa_dev_server_url.is_some() {
+                return Ok(());
+            }
+            match tokio::fs::metadata(Path::new(spa_dist_dir)).await {
+                Ok(metadata) if metadata.is_dir() => Ok(()),
+                Ok(_) => Err(format!("{spa_dist_dir} is not a directory")),
+                Err(err) => Err(format!("{spa_dist_dir}: {err}")),
+            }
+        }),
+    );
+
+    let checks: Vec<DependencyCheck> = vec![database, search, spa];
+    let shutting_down: bool = is_shutting_down();
+    ReadinessReport {
+        ready: !shutting_down && checks.iter().all(|check| check.healthy),
+        shutting_down,
+        checks,
+    }
+}
+
+/// Runs a single dependency check, failing it if it doesn't finish within the timeout
+async fn check(
+    name: &str,
+    timeout: Duration,
+    dependency_check: impl Future<Output = Result<(), String>>,
+) -> DependencyCheck {
+    let start: Instant = Instant::now();
+    let result: Result<(), String> = tokio::time::timeout(timeout, dependency_check)
+        .await
+        .unwrap_or_else(|_| Err(format!("Timed out after {}ms", timeout.as_millis())));
+
+    DependencyCheck {
+        name: name.to_string(),
+        healthy: result.is_ok(),
+        duration_ms: u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX),
+        error: result.err(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{services::configs::AppConfig, DB_MIGRATOR};
+    use meilisearch_sdk::client::Client;
+    use pretty_assertions::assert_eq;
+    use sqlx::PgPool;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn health_check_timeout() {
+        let check: DependencyCheck = check("slow", Duration::from_millis(10), async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        })
+        .await;
+        assert!(!check.healthy);
+        assert_eq!(check.error.unwrap(), "Timed out after 10ms");
+    }
+
+    #[sqlx::test(migrator = "DB_MIGRATOR")]
+    async fn health_check_readiness(pool: PgPool) {
+        let app_state = AppState {
+            config: Arc::new(AppConfig::load().unwrap()),
+            db_pool: pool,
+            // Nothing listens on the discard port, so Search is down
+            search_client: Client::new("http://127.0.0.1:9", None::<String>).unwrap(),
+        };
+
+        let report: ReadinessReport =
+            check_readiness(&app_state, "src", Duration::from_secs(2)).await;
+        assert!(!report.ready);
+
+        let healthy: Vec<(&str, bool)> = report
+            .checks
+            .iter()
+            .map(|check| (check.name.as_str(), check.healthy))
+            .collect();
+        assert_eq!(
+            healthy,
+            vec![
+                ("database", true),
+                ("search", false),
+                ("spa_dist_dir", true)
+            ]
+        );
+    }
+}
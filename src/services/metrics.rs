@@ -0,0 +1,160 @@
+//! Provides metrics about our app server in the Prometheus text format, served at `/metrics`.
+//!
+//! - `http_requests_total` and `http_request_duration_seconds` are labeled by method, route template (e.g.
+//!   `/api/players/:id` rather than the raw path, so IDs don't explode the number of series) and status.
+//! - `db_pool_connections` (by state: idle or in use) and `db_pool_max_connections` are read from the sqlx pool when
+//!   scraped. sqlx doesn't expose how many tasks are waiting for a connection, so a saturated pool shows up as in use
+//!   connections reaching the max.
+//! - `search_request_duration_seconds` and `search_request_failures_total` are recorded by [`services::search`](super::search)
+//!   for each call to Search, labeled by operation.
+//!
+//! Setting `admin_server_url` serves `/metrics` on the admin listener (only reachable internally) rather than on the app
+//! server.
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::services::app_server::AppState;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Route label for requests that didn't match a route, e.g. 404s
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Histogram buckets (in seconds) for all our duration metrics
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static PROMETHEUS_HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Returns the handle to our Prometheus recorder, installing it as the global recorder the first time it's called
+fn get_prometheus_handle() -> &'static PrometheusHandle {
+    PROMETHEUS_HANDLE.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Suffix(String::from("duration_seconds")),
+                DURATION_BUCKETS,
+            )
+            .expect("Metric buckets must not be empty")
+            .install_recorder()
+            .expect("Failed to install the Prometheus metrics recorder")
+    })
+}
+
+/// Installs our metrics recorder, so metrics recorded from now on are kept. Needs to be called before serving
+/// requests.
+pub fn init_metrics() {
+    get_prometheus_handle();
+}
+
+/// Returns the route serving our metrics
+pub fn routes() -> Router<AppState> {
+    Router::new().route(METRICS_PATH, get(render_metrics))
+}
+
+async fn render_metrics(State(app_state): State<AppState>) -> impl IntoResponse {
+    let db_pool = &app_state.db_pool;
+    let idle: usize = db_pool.num_idle();
+    let size: usize = usize::try_from(db_pool.size()).unwrap_or(usize::MAX);
+    metrics::gauge!("db_pool_connections", "state" => "idle").set(idle as f64);
+    metrics::gauge!("db_pool_connections", "state" => "in_use")
+        .set(size.saturating_sub(idle) as f64);
+    metrics::gauge!("db_pool_max_connections")
+        .set(f64::from(db_pool.options().get_max_connections()));
+
+    let handle: &PrometheusHandle = get_prometheus_handle();
+    // Drains histogram samples, which the recorder otherwise leaves to a background task we don't run
+    handle.run_upkeep();
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        handle.render(),
+    )
+}
+
+/// Middleware that records the count and duration of requests. Add it with `Router::layer`, so it sees the route
+/// template requests matched.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let start: Instant = Instant::now();
+    let method: String = request.method().to_string();
+    let route: String = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED_ROUTE, MatchedPath::as_str)
+        .to_string();
+
+    let response: Response = next.run(request).await;
+
+    let labels: [(&str, String); 3] = [
+        ("method", method),
+        ("route", route),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!("http_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Records the outcome of a call to Search
+pub fn record_search_request(operation: &'static str, duration: Duration, succeeded: bool) {
+    metrics::histogram!("search_request_duration_seconds", "operation" => operation)
+        .record(duration.as_secs_f64());
+    if !succeeded {
+        metrics::counter!("search_request_failures_total", "operation" => operation).increment(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{services::configs::AppConfig, DB_MIGRATOR};
+    use axum::{middleware, Router};
+    use axum_test::TestServer;
+    use meilisearch_sdk::client::Client;
+    use sqlx::PgPool;
+    use std::sync::Arc;
+
+    #[sqlx::test(migrator = "DB_MIGRATOR")]
+    async fn metrics_render(pool: PgPool) {
+        init_metrics();
+        let app_state = AppState {
+            config: Arc::new(AppConfig::load().unwrap()),
+            db_pool: pool,
+            search_client: Client::new("http://127.0.0.1:9", None::<String>).unwrap(),
+        };
+        let router: Router = Router::new()
+            .route("/api/test/:id", get(|| async { "ok" }))
+            .merge(routes())
+            .layer(middleware::from_fn(track_requests))
+            .with_state(app_state);
+        let server = TestServer::new(router).unwrap();
+
+        server.get("/api/test/42").await;
+        record_search_request("test", Duration::from_millis(20), false);
+
+        let metrics: String = server.get(METRICS_PATH).await.text();
+        assert!(metrics
+            .contains(r#"http_requests_total{method="GET",route="/api/test/:id",status="200"} 1"#));
+        assert!(metrics.contains("http_request_duration_seconds_bucket"));
+        assert!(metrics.contains(r#"db_pool_connections{state="idle"}"#));
+        assert!(metrics.contains(r#"search_request_failures_total{operation="test"} 1"#));
+    }
+}
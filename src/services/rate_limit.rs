@@ -0,0 +1,566 @@
+//! Provides per-client rate limiting of our APIs, so a runaway script can't hammer them (or the services behind them,
+//! like Search).
+//!
+//! Each client gets a token bucket per [`RouteGroup`], which holds up to the group's burst of requests and refills at
+//! its requests per minute. Clients are identified by their API key or user (see [`Principal`]), falling back to their
+//! IP address for anonymous requests (see [`ClientIp`]). Anonymous requests from an unknown address, i.e. over a Unix
+//! domain socket without `app_server_unix_client_ip_header` set, aren't limited. Responses carry `RateLimit-Limit`,
+//! `RateLimit-Remaining` and `RateLimit-Reset` headers, and rejected requests get a 429 with a `Retry-After` header.
+//!
+//! Buckets are kept in memory by default, up to [`MAX_MEMORY_BUCKETS`] of them with the least recently used one evicted
+//! to make room. Setting `rate_limit_store = "postgres"` keeps them in the DB instead, so limits hold across multiple
+//! app server instances, with idle buckets deleted periodically (see [`init_bucket_pruning`]).
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use colored::Colorize;
+use sqlx::{Pool, Postgres};
+use tracing::Instrument;
+
+use crate::{
+    api::{endpoints, errors::ApiError},
+    services::{
+        app_server::serve::ClientIp,
+        auth::{Principal, Subject},
+        configs::ConfigReader,
+        db,
+    },
+};
+
+/// The most buckets the in-memory store holds. An evicted client just gets a new, full bucket.
+pub const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+/// How often the Postgres store's idle buckets are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+static RATE_LIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+static RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+static RATE_LIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// The groups of routes that are limited separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    /// Player search, where every request is a call to our Search service
+    Search,
+    /// Every other API
+    Api,
+}
+
+impl RouteGroup {
+    pub fn for_path(path: &str) -> Self {
+        if path == endpoints::build_player_search_path() {
+            RouteGroup::Search
+        } else {
+            RouteGroup::Api
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RouteGroup::Search => "search",
+            RouteGroup::Api => "api",
+        }
+    }
+}
+
+/// The rate limit for a route group
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    /// The most requests a client can make at once, i.e. the size of their bucket
+    pub burst: u32,
+    pub requests_per_minute: u32,
+}
+
+impl Limit {
+    /// Reads a group's limit from its rate_limit_[group]_* config values. Returns None if the group isn't limited.
+    fn from_config(reader: &mut ConfigReader, group: RouteGroup) -> Option<Self> {
+        let requests_per_minute: u32 =
+            reader.get(&format!("rate_limit_{}_requests_per_minute", group.name()));
+        if requests_per_minute == 0 {
+            return None;
+        }
+
+        Some(Limit {
+            burst: reader
+                .get::<u32>(&format!("rate_limit_{}_burst", group.name()))
+                .max(1),
+            requests_per_minute,
+        })
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        f64::from(self.requests_per_minute) / 60.0
+    }
+}
+
+/// The outcome of taking a token from a bucket
+#[derive(Debug, Clone, Copy)]
+struct Decision {
+    allowed: bool,
+    /// Tokens left in the bucket afterwards
+    remaining: f64,
+}
+
+/// A client's token bucket, as kept in memory
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn take(&mut self, limit: Limit, now: Instant) -> Decision {
+        let refilled: f64 = self.tokens
+            + now.duration_since(self.updated_at).as_secs_f64() * limit.refill_per_sec();
+        self.tokens = refilled.min(f64::from(limit.burst));
+        self.updated_at = now;
+
+        let allowed: bool = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            remaining: self.tokens,
+        }
+    }
+}
+
+/// Which kind of [`RateLimitStore`] to keep token buckets in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitStoreKind {
+    #[default]
+    Memory,
+    Postgres,
+}
+
+impl FromStr for RateLimitStoreKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "memory" => Ok(RateLimitStoreKind::Memory),
+            "postgres" => Ok(RateLimitStoreKind::Postgres),
+            _ => Err(String::from("must be \"memory\" or \"postgres\"")),
+        }
+    }
+}
+
+/// Our rate limits, from the rate_limit_* config values
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub store: RateLimitStoreKind,
+    /// Groups without a limit aren't rate limited
+    pub limits: HashMap<RouteGroup, Limit>,
+}
+
+impl RateLimitConfig {
+    pub fn from_config(reader: &mut ConfigReader) -> Self {
+        RateLimitConfig {
+            store: reader.get("rate_limit_store"),
+            limits: [RouteGroup::Search, RouteGroup::Api]
+                .into_iter()
+                .filter_map(|group| Limit::from_config(reader, group).map(|limit| (group, limit)))
+                .collect(),
+        }
+    }
+
+    /// How long an empty bucket takes to refill, after which it's the same as a new one
+    fn max_refill_secs(&self) -> f64 {
+        self.limits
+            .values()
+            .map(|limit| f64::from(limit.burst) / limit.refill_per_sec())
+            .fold(0.0, f64::max)
+    }
+}
+
+/// Where token buckets are kept
+#[derive(Clone)]
+pub enum RateLimitStore {
+    Memory(MemoryBuckets),
+    Postgres(Pool<Postgres>),
+}
+
+/// Token buckets kept in this app server's memory, by client key. Clones share the buckets.
+#[derive(Clone)]
+pub struct MemoryBuckets(Arc<Mutex<LruBuckets>>);
+
+impl MemoryBuckets {
+    /// Holds up to the capacity of buckets, evicting the least recently used one to make room
+    pub fn with_capacity(capacity: usize) -> Self {
+        MemoryBuckets(Arc::new(Mutex::new(LruBuckets {
+            capacity: capacity.max(1),
+            buckets: HashMap::new(),
+            by_last_used: BTreeMap::new(),
+            next_use: 0,
+        })))
+    }
+}
+
+impl Default for MemoryBuckets {
+    fn default() -> Self {
+        MemoryBuckets::with_capacity(MAX_MEMORY_BUCKETS)
+    }
+}
+
+/// Buckets in least recently used order, so the one to evict is found without scanning them all
+struct LruBuckets {
+    capacity: usize,
+    /// Each bucket, with when it was last used
+    buckets: HashMap<String, (Bucket, u64)>,
+    /// Each bucket's key, by when it was last used
+    by_last_used: BTreeMap<u64, String>,
+    /// Counts uses of the buckets, to order them
+    next_use: u64,
+}
+
+impl LruBuckets {
+    fn take(&mut self, key: &str, limit: Limit, now: Instant) -> Decision {
+        let used: u64 = self.next_use;
+        self.next_use += 1;
+
+        if let Some((bucket, last_used)) = self.buckets.get_mut(key) {
+            self.by_last_used.remove(last_used);
+            self.by_last_used.insert(used, key.to_string());
+            *last_used = used;
+            return bucket.take(limit, now);
+        }
+
+        if self.buckets.len() >= self.capacity {
+            if let Some((_, evicted)) = self.by_last_used.pop_first() {
+                self.buckets.remove(&evicted);
+            }
+        }
+        let mut bucket = Bucket {
+            tokens: f64::from(limit.burst),
+            updated_at: now,
+        };
+        let decision: Decision = bucket.take(limit, now);
+        self.buckets.insert(key.to_string(), (bucket, used));
+        self.by_last_used.insert(used, key.to_string());
+        decision
+    }
+}
+
+/// Rate limits requests, see the module docs. Add it to a router with:
+/// `.route_layer(middleware::from_fn_with_state(rate_limiter, rate_limit::limit))`
+#[derive(Clone)]
+pub struct RateLimiter {
+    limits: Arc<HashMap<RouteGroup, Limit>>,
+    store: RateLimitStore,
+}
+
+impl RateLimiter {
+    pub fn new(limits: HashMap<RouteGroup, Limit>, store: RateLimitStore) -> Self {
+        RateLimiter {
+            limits: Arc::new(limits),
+            store,
+        }
+    }
+
+    /// Creates a rate limiter from our rate limit config
+    pub fn from_config(config: &RateLimitConfig, db_pool: &Pool<Postgres>) -> Self {
+        let store: RateLimitStore = match config.store {
+            RateLimitStoreKind::Memory => RateLimitStore::Memory(MemoryBuckets::default()),
+            RateLimitStoreKind::Postgres => RateLimitStore::Postgres(db_pool.clone()),
+        };
+
+        RateLimiter::new(config.limits.clone(), store)
+    }
+
+    /// Takes a token from the key's bucket
+    async fn take(&self, key: &str, limit: Limit) -> Result<Decision, sqlx::Error> {
+        match &self.store {
+            RateLimitStore::Memory(buckets) => {
+                Ok(buckets.0.lock().unwrap().take(key, limit, Instant::now()))
+            }
+            RateLimitStore::Postgres(db_pool) => {
+                // Refills and takes from the bucket in a single statement, so concurrent requests from the same
+                // client on different instances can't both take the last token
+                let row = sqlx::query!(
+                    r#"INSERT INTO rate_limit_bucket AS bucket
+                    (key, tokens, allowed)
+                    VALUES ($1, $2::float8 - 1, true)
+                    ON CONFLICT (key) DO UPDATE SET
+                        tokens = least($2, bucket.tokens + extract(epoch from now() - bucket.updated_at)::float8 * $3)
+                            - (least($2, bucket.tokens + extract(epoch from now() - bucket.updated_at)::float8 * $3) >= 1)::int,
+                        allowed = least($2, bucket.tokens + extract(epoch from now() - bucket.updated_at)::float8 * $3) >= 1,
+                        updated_at = now()
+                    RETURNING tokens, allowed"#,
+                    key,
+                    f64::from(limit.burst),
+                    limit.refill_per_sec()
+                )
+                .fetch_one(db_pool)
+                .instrument(db::query_span("UPSERT rate_limit_bucket"))
+                .await?;
+
+                Ok(Decision {
+                    allowed: row.allowed,
+                    remaining: row.tokens,
+                })
+            }
+        }
+    }
+}
+
+/// Deletes the Postgres store's idle buckets in the background every [`PRUNE_INTERVAL`]. A bucket that's been idle for
+/// long enough to refill is the same as a new one, so deleting it changes nothing for its client.
+pub fn init_bucket_pruning(config: &RateLimitConfig, db_pool: &Pool<Postgres>) {
+    if config.store != RateLimitStoreKind::Postgres || config.limits.is_empty() {
+        return;
+    }
+
+    let db_pool: Pool<Postgres> = db_pool.clone();
+    let max_idle_secs: f64 = config.max_refill_secs();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match prune_buckets(&db_pool, max_idle_secs).await {
+                Ok(deleted) => tracing::debug!("Deleted {deleted} idle rate limit buckets"),
+                Err(err) => tracing::warn!("Failed to delete idle rate limit buckets: {err}"),
+            }
+        }
+    });
+}
+
+/// Deletes the buckets that haven't been used for the number of seconds, returning how many were deleted
+async fn prune_buckets(db_pool: &Pool<Postgres>, max_idle_secs: f64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        "delete from rate_limit_bucket where updated_at < now() - make_interval(secs => $1)",
+        max_idle_secs
+    )
+    .execute(db_pool)
+    .instrument(db::query_span("DELETE rate_limit_bucket"))
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Middleware that rate limits requests, see the module docs. It needs to run after the
+/// [`authenticate`](crate::services::auth::authenticate) middleware to limit by API key or user.
+pub async fn limit(
+    State(rate_limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let group: RouteGroup = RouteGroup::for_path(
+        request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str)
+            .unwrap_or(request.uri().path()),
+    );
+    let Some(limit) = rate_limiter.limits.get(&group).copied() else {
+        return next.run(request).await;
+    };
+
+    let Some(client_key) = get_client_key(&request) else {
+        return next.run(request).await;
+    };
+    let key: String = format!("{}:{client_key}", group.name());
+    let decision: Decision = match rate_limiter.take(&key, limit).await {
+        Ok(decision) => decision,
+        Err(err) => {
+            // Better to let requests through than to fail them all because the store is unavailable
+            tracing::error!("{} {:?}", "Rate limit store error".red(), err);
+            return next.run(request).await;
+        }
+    };
+
+    let mut response: Response = if decision.allowed {
+        next.run(request).await
+    } else {
+        let retry_after: f64 = (1.0 - decision.remaining) / limit.refill_per_sec();
+        let mut response: Response = ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many requests, please slow down",
+        )
+        .into_response();
+        response
+            .headers_mut()
+            .insert(axum::http::header::RETRY_AFTER, to_header_secs(retry_after));
+        response
+    };
+
+    add_rate_limit_headers(response.headers_mut(), limit, decision);
+    response
+}
+
+/// Identifies the client making the request. None for an anonymous client whose IP address isn't known, rather than
+/// sharing one bucket between all of them.
+fn get_client_key(request: &Request) -> Option<String> {
+    match request
+        .extensions()
+        .get::<Principal>()
+        .map(|principal| &principal.subject)
+    {
+        Some(Subject::ApiKey(id)) => Some(format!("api_key:{id}")),
+        Some(Subject::User(id)) => Some(format!("user:{id}")),
+        _ => request
+            .extensions()
+            .get::<ClientIp>()
+            .map(|ClientIp(ip)| format!("ip:{ip}")),
+    }
+}
+
+fn add_rate_limit_headers(headers: &mut HeaderMap, limit: Limit, decision: Decision) {
+    let until_full: f64 = (f64::from(limit.burst) - decision.remaining) / limit.refill_per_sec();
+
+    headers.insert(RATE_LIMIT_LIMIT.clone(), HeaderValue::from(limit.burst));
+    headers.insert(
+        RATE_LIMIT_REMAINING.clone(),
+        HeaderValue::from(decision.remaining.max(0.0).floor() as u64),
+    );
+    headers.insert(RATE_LIMIT_RESET.clone(), to_header_secs(until_full));
+}
+
+/// Header values for durations are whole seconds, rounded up so clients don't retry too early
+fn to_header_secs(secs: f64) -> HeaderValue {
+    HeaderValue::from(secs.max(0.0).ceil() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::DB_MIGRATOR;
+    use axum::{middleware, routing::get, Extension, Router};
+    use axum_test::TestServer;
+    use pretty_assertions::assert_eq;
+    use sqlx::PgPool;
+    use std::time::Duration;
+
+    const LIMIT: Limit = Limit {
+        burst: 2,
+        requests_per_minute: 60,
+    };
+
+    #[test]
+    fn rate_limit_bucket_refills() {
+        let start: Instant = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 2.0,
+            updated_at: start,
+        };
+
+        assert!(bucket.take(LIMIT, start).allowed);
+        assert!(bucket.take(LIMIT, start).allowed);
+        assert!(!bucket.take(LIMIT, start).allowed);
+
+        // Refills one token per second, up to the burst
+        assert!(bucket.take(LIMIT, start + Duration::from_secs(1)).allowed);
+        assert!(!bucket.take(LIMIT, start + Duration::from_secs(1)).allowed);
+    }
+
+    #[test]
+    fn rate_limit_memory_store_evicts_least_recently_used() {
+        let buckets = MemoryBuckets::with_capacity(2);
+        let mut buckets = buckets.0.lock().unwrap();
+        let now: Instant = Instant::now();
+
+        buckets.take("a", LIMIT, now);
+        buckets.take("b", LIMIT, now);
+        // Using "a" again makes "b" the least recently used, so it's evicted for "c"
+        assert!(buckets.take("a", LIMIT, now).allowed);
+        buckets.take("c", LIMIT, now);
+
+        assert_eq!(buckets.buckets.len(), 2);
+        assert_eq!(buckets.by_last_used.len(), 2);
+        assert!(!buckets.buckets.contains_key("b"));
+        assert!(!buckets.take("a", LIMIT, now).allowed);
+    }
+
+    #[test]
+    fn rate_limit_route_group() {
+        assert_eq!(
+            RouteGroup::for_path(&endpoints::build_player_search_path()),
+            RouteGroup::Search
+        );
+        assert_eq!(
+            RouteGroup::for_path(endpoints::PLAYERS_API),
+            RouteGroup::Api
+        );
+    }
+
+    /// Starts a server with a rate limited /api/test route, whose anonymous clients have the IP address if it's set
+    fn get_test_server(store: RateLimitStore, client_ip: Option<ClientIp>) -> TestServer {
+        let rate_limiter = RateLimiter::new(HashMap::from([(RouteGroup::Api, LIMIT)]), store);
+        let mut router: Router = Router::new()
+            .route("/api/test", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(rate_limiter, limit));
+        if let Some(client_ip) = client_ip {
+            router = router.layer(Extension(client_ip));
+        }
+        TestServer::new(router).unwrap()
+    }
+
+    /// Runs requests through the middleware until the burst is used up, for either store
+    async fn validate_limit(store: RateLimitStore) {
+        let server = get_test_server(store, Some(ClientIp([127, 0, 0, 1].into())));
+
+        let response = server.get("/api/test").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.header("ratelimit-limit"), "2");
+        assert_eq!(response.header("ratelimit-remaining"), "1");
+
+        server.get("/api/test").await;
+
+        let response = server.get("/api/test").await;
+        assert_eq!(response.status_code(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.header("retry-after"), "1");
+        assert_eq!(response.header("ratelimit-remaining"), "0");
+    }
+
+    #[tokio::test]
+    async fn rate_limit_memory_store() {
+        validate_limit(RateLimitStore::Memory(MemoryBuckets::default())).await;
+    }
+
+    /// Validates anonymous clients without a known IP address, e.g. over a Unix domain socket, don't share a limit
+    #[tokio::test]
+    async fn rate_limit_unknown_client() {
+        let server = get_test_server(RateLimitStore::Memory(MemoryBuckets::default()), None);
+        for _ in 0..3 {
+            let response = server.get("/api/test").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert!(response.maybe_header("ratelimit-limit").is_none());
+        }
+    }
+
+    #[sqlx::test(migrator = "DB_MIGRATOR")]
+    async fn rate_limit_postgres_store(pool: PgPool) {
+        validate_limit(RateLimitStore::Postgres(pool)).await;
+    }
+
+    #[sqlx::test(migrator = "DB_MIGRATOR")]
+    async fn rate_limit_postgres_store_prunes_idle_buckets(pool: PgPool) {
+        sqlx::query(
+            r#"insert into rate_limit_bucket (key, tokens, allowed, updated_at) values
+            ('api:ip:idle', 0, false, now() - interval '1 hour'),
+            ('api:ip:active', 0, false, now())"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(prune_buckets(&pool, 60.0).await.unwrap(), 1);
+        let keys: Vec<String> = sqlx::query_scalar("select key from rate_limit_bucket")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(keys, vec![String::from("api:ip:active")]);
+    }
+}
@@ -0,0 +1,145 @@
+//! Provides request IDs, so a request can be followed through our logs and matched to what the caller saw.
+//!
+//! The [`propagate`] middleware takes the caller's `X-Request-Id` header, or generates one when it's missing or
+//! invalid, and echoes it back in the response. The ID is recorded on the request's tracing span (see
+//! [`make_request_span`]) so every log line inside handlers and services carries it, and is included in the JSON body
+//! of API errors.
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+use super::tracing as app_tracing;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest caller provided request ID we accept, so IDs can't be used to bloat our logs
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Middleware that assigns the request its ID. Needs to run before the `TraceLayer`, so the ID is there when the
+/// request's span is created.
+pub async fn propagate(mut request: Request, next: Next) -> Response {
+    let request_id: String = match request.headers().get(&X_REQUEST_ID) {
+        Some(value) if is_valid_request_id(value) => value.to_str().unwrap().to_string(),
+        _ => Uuid::new_v4().to_string(),
+    };
+
+    // The ID was validated or generated above, so it's always a valid header value
+    let header_value: HeaderValue = HeaderValue::from_str(&request_id).unwrap();
+    request
+        .headers_mut()
+        .insert(X_REQUEST_ID.clone(), header_value.clone());
+
+    let mut response: Response = REQUEST_ID.scope(request_id, next.run(request)).await;
+    response
+        .headers_mut()
+        .insert(X_REQUEST_ID.clone(), header_value);
+    response
+}
+
+/// Returns the ID of the request currently being handled, if called while handling one
+pub fn get_current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+/// Creates the tracing span for a request, for use with `TraceLayer::make_span_with`. The span joins the caller's
+/// trace when the request has a `traceparent` header.
+pub fn make_request_span<B>(request: &axum::http::Request<B>) -> tracing::Span {
+    let request_id: &str = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    // The route template rather than the raw path, so spans for the same endpoint are grouped together
+    let route: &str = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(request.uri().path(), MatchedPath::as_str);
+
+    let span: tracing::Span = tracing::info_span!(
+        "request",
+        otel.name = format!("{} {}", request.method(), route),
+        otel.kind = "server",
+        request_id,
+        method = %request.method(),
+        uri = %request.uri(),
+    );
+    app_tracing::set_remote_parent(&span, request.headers());
+    span
+}
+
+/// Only accepts IDs made of letters, digits, '-', '_' and '.', so they can't inject anything into our logs
+fn is_valid_request_id(value: &HeaderValue) -> bool {
+    let bytes: &[u8] = value.as_bytes();
+    !bytes.is_empty()
+        && bytes.len() <= MAX_REQUEST_ID_LEN
+        && bytes
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::api::errors::ApiError;
+    use axum::{middleware, response::IntoResponse, routing::get, Router};
+    use axum_test::TestServer;
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+
+    fn get_test_server() -> TestServer {
+        let router: Router = Router::new()
+            .route(
+                "/api/test",
+                get(|| async { ApiError::not_found("No such test").into_response() }),
+            )
+            .layer(middleware::from_fn(propagate));
+        TestServer::new(router).unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_id_generated() {
+        let response = get_test_server().get("/api/test").await;
+
+        let request_id: String = response.header(&X_REQUEST_ID).to_str().unwrap().to_string();
+        assert!(Uuid::parse_str(&request_id).is_ok());
+        assert_eq!(
+            response.json::<Value>()["request_id"],
+            Value::String(request_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn request_id_from_caller() {
+        let server = get_test_server();
+
+        let response = server
+            .get("/api/test")
+            .add_header(X_REQUEST_ID.clone(), HeaderValue::from_static("abc-123"))
+            .await;
+        assert_eq!(response.header(&X_REQUEST_ID), "abc-123");
+
+        // IDs that could inject into our logs are replaced
+        let response = server
+            .get("/api/test")
+            .add_header(
+                X_REQUEST_ID.clone(),
+                HeaderValue::from_static("abc 123\" level=ERROR"),
+            )
+            .await;
+        assert_ne!(response.header(&X_REQUEST_ID), "abc 123\" level=ERROR");
+    }
+
+    #[test]
+    fn request_id_outside_request() {
+        assert_eq!(get_current_request_id(), None);
+    }
+}
@@ -5,52 +5,40 @@
 //! DB replication/notification type approach.  See [this post](https://www.meilisearch.com/docs/guides/database/meilisync_postgresql)
 //! about using meilisearch to index data in Postgres.
 //!
-use lazy_static::lazy_static;
 use std::time;
 
 use colored::Colorize;
 use meilisearch_sdk::{client::Client, errors::Error, task_info::TaskInfo, tasks::Task};
+use tracing::Instrument;
 
 use crate::resources::Player;
 
-use super::configs;
-
-lazy_static! {
-    static ref PLAYER_SEARCH_INDEX: String = configs::get_env_var_or_panic("player_search_index");
-}
+use super::{configs::AppConfig, metrics};
 
 /// Get a Client to our Search server.
 ///
 /// Right now, this runs with the Master key, but eventually we should make clients that only run with
 /// either the "Admin API key" or the general "Search API key":
 /// <https://www.meilisearch.com/docs/learn/security/differences_master_api_keys>
-pub fn get_client() -> Result<Client, Error> {
+pub fn get_client(app_config: &AppConfig) -> Result<Client, Error> {
     Client::new(
-        configs::get_env_var_or_panic("search_server_url"),
-        Some(configs::get_env_var_or_panic("search_master_key")),
+        &app_config.search_server_url,
+        Some(app_config.search_master_key.expose()),
     )
 }
 
-pub fn get_player_index_name() -> &'static str {
-    PLAYER_SEARCH_INDEX.as_str()
-}
-
-/// Search for player(s) that match the term.  
-pub async fn player_search(search_client: &Client, term: &str) -> Vec<Player> {
-    player_search_with_idx(search_client, term, get_player_index_name()).await
-}
-
-/// Search for player(s) that match the term against a specific index (i.e not the default index).  
-/// /// Note: this is broken out from the search function above for testing purposes.
-async fn player_search_with_idx(search_client: &Client, term: &str, index: &str) -> Vec<Player> {
-    let search_results = search_client
+/// Search for player(s) that match the term in the Players index, i.e. our `player_search_index` config value
+pub async fn player_search(search_client: &Client, index: &str, term: &str) -> Vec<Player> {
+    let start: time::Instant = time::Instant::now();
+    let result = search_client
         .index(index)
         .search()
         .with_query(term)
         .execute::<Player>()
-        .await
-        .unwrap()
-        .hits;
+        .instrument(request_span("search", index))
+        .await;
+    metrics::record_search_request("search", start.elapsed(), result.is_ok());
+    let search_results = result.unwrap().hits;
 
     let mut players: Vec<Player> = Vec::with_capacity(search_results.len());
     for player in search_results {
@@ -60,9 +48,9 @@ async fn player_search_with_idx(search_client: &Client, term: &str, index: &str)
     players
 }
 
-/// Upserts a Player to the default Players search index
-pub async fn index_player(search_client: &Client, player: &Player) {
-    let result = index_player_with_idx(search_client, player, get_player_index_name()).await;
+/// Upserts a Player to the Players search index
+pub async fn index_player(search_client: &Client, index: &str, player: &Player) {
+    let result = index_player_with_idx(search_client, player, index).await;
     if result.is_err() {
         // For production, we'll need to send some alert or feed some operator system to monitor and bulk fix
         tracing::error!("{} {:?}", "Serch indexing error".red(), result.err());
@@ -76,12 +64,27 @@ async fn index_player_with_idx(
     player: &Player,
     index: &str,
 ) -> Result<TaskInfo, Error> {
-    let mut players: Vec<&Player> = Vec::with_capacity(1);
-    players.push(player);
-    search_client
+    let players: Vec<&Player> = vec![player];
+
+    let start: time::Instant = time::Instant::now();
+    let result: Result<TaskInfo, Error> = search_client
         .index(index)
         .add_documents(&players, Some("id"))
-        .await
+        .instrument(request_span("index", index))
+        .await;
+    metrics::record_search_request("index", start.elapsed(), result.is_ok());
+    result
+}
+
+/// Creates a span for a call to Search, so it's exported as its own span when trace export is on
+fn request_span(operation: &'static str, index: &str) -> tracing::Span {
+    tracing::info_span!(
+        "search.request",
+        otel.name = format!("search {operation}"),
+        otel.kind = "client",
+        search.operation = operation,
+        search.index = index
+    )
 }
 
 /// Convienence utility to wait for an indexing operation to complete. This function hardcodes checking
@@ -124,13 +127,15 @@ mod tests {
     async fn search_player_search(pool: PgPool) {
         let test_index_name: String = get_unique_test_index_name("search_player_search");
         // Initialize and seed the search index (using our test's name for the index)
-        let search_client: Client =
-            dev_and_test_utils::search_service_init_and_seed_with_idx(pool, &test_index_name)
-                .await
-                .unwrap();
+        let search_client: Client = dev_and_test_utils::search_service_init_and_seed_with_idx(
+            &AppConfig::load().unwrap(),
+            pool,
+            &test_index_name,
+        )
+        .await
+        .unwrap();
 
-        let players: Vec<Player> =
-            player_search_with_idx(&search_client, "kobe", &test_index_name).await;
+        let players: Vec<Player> = player_search(&search_client, &test_index_name, "kobe").await;
         assert_eq!(players.len(), 1);
         assert_eq!(players.first().unwrap().username, "kobe");
 
@@ -143,9 +148,10 @@ mod tests {
         let test_index_name = get_unique_test_index_name("search_index_player");
 
         // Initialize the search index (using our test's name for the index)
-        let search_client: Client = dev_and_test_utils::search_service_init(&test_index_name)
-            .await
-            .unwrap();
+        let search_client: Client =
+            dev_and_test_utils::search_service_init(&AppConfig::load().unwrap(), &test_index_name)
+                .await
+                .unwrap();
 
         let username: &str = "rambo";
         let player = Player {
@@ -166,8 +172,7 @@ mod tests {
             .await
             .unwrap();
 
-        let players: Vec<Player> =
-            player_search_with_idx(&search_client, username, &test_index_name).await;
+        let players: Vec<Player> = player_search(&search_client, &test_index_name, username).await;
         assert_eq!(players.len(), 1);
         assert_eq!(players.first().unwrap().username, username);
 
@@ -181,6 +186,9 @@ mod tests {
     // 2. You're using a testing execution method that doesn't pass in our testing configs (in .cargo/config-test.toml))
     #[tokio::test]
     async fn search_validate_test_index() {
-        assert!(get_player_index_name().ends_with("_TEST"));
+        assert!(AppConfig::load()
+            .unwrap()
+            .player_search_index
+            .ends_with("_TEST"));
     }
 }
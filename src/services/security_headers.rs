@@ -0,0 +1,299 @@
+//! Provides the security headers every response gets, to harden the SPA and other pages against clickjacking,
+//! content sniffing, protocol downgrades and cross-site scripting.
+//!
+//! Documents we render from a trusted template (i.e. the SPA's `index.html`, see [`spa::index`](super::spa::index))
+//! get a strict Content-Security-Policy with a fresh nonce per response, so we don't need `unsafe-inline`. The nonce is
+//! injected into their `<script>` and `<style>` tags from the request's [`CspNonce`], which they add to their response
+//! too. Any other HTML (e.g. from static mounts) gets a policy without a nonce, which lets it load scripts and styles
+//! from our origin but not run inline ones. Other responses (e.g. API JSON) get a policy that allows nothing, since
+//! they're never meant to render.
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+
+use super::configs::ConfigReader;
+
+/// Placeholder in the `security_content_security_policy` config value that's replaced with each response's nonce
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+/// Policy for responses that aren't HTML documents
+const NON_DOCUMENT_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; frame-ancestors 'none'";
+
+/// The security headers to add to responses, as set in the security_* config values
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    /// None when HSTS is turned off
+    strict_transport_security: Option<HeaderValue>,
+    referrer_policy: HeaderValue,
+    /// The policy for documents with a nonce, with [`NONCE_PLACEHOLDER`] where the nonce goes
+    document_content_security_policy: String,
+    /// The policy for other HTML documents
+    static_document_content_security_policy: HeaderValue,
+}
+
+impl SecurityHeaders {
+    /// Reads the headers from the security_* config values
+    pub fn from_config(reader: &mut ConfigReader) -> Self {
+        let hsts_max_age: u32 = reader.get("security_hsts_max_age");
+        let referrer_policy: String = reader.get_string("security_referrer_policy");
+        let content_security_policy: String = reader.get_string("security_content_security_policy");
+        let static_content_security_policy: String =
+            reader.get_string("security_static_content_security_policy");
+
+        SecurityHeaders::try_new(
+            hsts_max_age,
+            &referrer_policy,
+            &content_security_policy,
+            &static_content_security_policy,
+        )
+        .unwrap_or_else(|err| {
+            reader.add_problem("security", err);
+            SecurityHeaders::new(
+                0,
+                "no-referrer",
+                NON_DOCUMENT_CONTENT_SECURITY_POLICY,
+                NON_DOCUMENT_CONTENT_SECURITY_POLICY,
+            )
+        })
+    }
+
+    /// Creates the headers, panicking if any aren't valid header values. See [`SecurityHeaders::try_new`].
+    pub fn new(
+        hsts_max_age: u32,
+        referrer_policy: &str,
+        content_security_policy: &str,
+        static_content_security_policy: &str,
+    ) -> Self {
+        SecurityHeaders::try_new(
+            hsts_max_age,
+            referrer_policy,
+            content_security_policy,
+            static_content_security_policy,
+        )
+        .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Creates the headers. An `hsts_max_age` of 0 turns HSTS off. `content_security_policy` is the policy for
+    /// documents with a nonce, where any "{nonce}" is replaced with each response's nonce, and
+    /// `static_content_security_policy` the policy for other HTML documents.
+    pub fn try_new(
+        hsts_max_age: u32,
+        referrer_policy: &str,
+        content_security_policy: &str,
+        static_content_security_policy: &str,
+    ) -> Result<Self, String> {
+        // Check the policy with a nonce in it is a valid header value now, rather than failing every response later
+        HeaderValue::from_str(&content_security_policy.replace(NONCE_PLACEHOLDER, "nonce"))
+            .map_err(|_| "security_content_security_policy must be a valid header value")?;
+
+        Ok(SecurityHeaders {
+            strict_transport_security: (hsts_max_age > 0)
+                .then(|| HeaderValue::from_str(&format!("max-age={hsts_max_age}")).unwrap()),
+            referrer_policy: HeaderValue::from_str(referrer_policy)
+                .map_err(|_| "security_referrer_policy must be a valid header value")?,
+            document_content_security_policy: content_security_policy.to_string(),
+            static_document_content_security_policy: HeaderValue::from_str(
+                static_content_security_policy,
+            )
+            .map_err(|_| "security_static_content_security_policy must be a valid header value")?,
+        })
+    }
+}
+
+/// The nonce the response's Content-Security-Policy allows, for trusted templates to add to their tags. The
+/// [`set_security_headers`] middleware gives every request one, and only responses that have it in their extensions
+/// (i.e. whose document it was added to) get the policy with the nonce.
+#[derive(Debug, Clone)]
+pub struct CspNonce(Arc<str>);
+
+impl CspNonce {
+    fn generate() -> Self {
+        let mut bytes: [u8; 16] = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        CspNonce(STANDARD.encode(bytes).into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Adds the nonce attribute to every `<script>` and `<style>` tag in the document, which must be from a trusted
+    /// template since any markup injected into it would be allowed to run too
+    pub fn inject(&self, document: &str) -> String {
+        document
+            .replace("<script", &format!("<script nonce=\"{}\"", self.0))
+            .replace("<style", &format!("<style nonce=\"{}\"", self.0))
+    }
+}
+
+/// Extracts the request's nonce. Use `Option<CspNonce>` in handlers, since it's missing without the middleware.
+#[async_trait]
+impl<S> FromRequestParts<S> for CspNonce
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<CspNonce>()
+            .cloned()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// Middleware that adds the security headers to every response, and gives the request a [`CspNonce`] for trusted
+/// templates to add to their documents
+pub async fn set_security_headers(
+    State(security_headers): State<SecurityHeaders>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    request.extensions_mut().insert(CspNonce::generate());
+    let mut response: Response = next.run(request).await;
+
+    let content_security_policy: HeaderValue = match response.extensions().get::<CspNonce>() {
+        Some(nonce) => HeaderValue::from_str(
+            &security_headers
+                .document_content_security_policy
+                .replace(NONCE_PLACEHOLDER, nonce.as_str()),
+        )
+        .unwrap(),
+        None if is_html_document(response.headers()) => security_headers
+            .static_document_content_security_policy
+            .clone(),
+        None => HeaderValue::from_static(NON_DOCUMENT_CONTENT_SECURITY_POLICY),
+    };
+    let headers: &mut HeaderMap = response.headers_mut();
+    headers.insert(header::CONTENT_SECURITY_POLICY, content_security_policy);
+
+    if let Some(strict_transport_security) = &security_headers.strict_transport_security {
+        headers.insert(
+            header::STRICT_TRANSPORT_SECURITY,
+            strict_transport_security.clone(),
+        );
+    }
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::REFERRER_POLICY,
+        security_headers.referrer_policy.clone(),
+    );
+    // For older browsers that don't support the CSP frame-ancestors directive
+    headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+
+    response
+}
+
+pub fn is_html_document(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use axum::{middleware, response::Html, routing::get, Extension, Json, Router};
+    use axum_test::TestServer;
+    use pretty_assertions::assert_eq;
+
+    fn get_test_server() -> TestServer {
+        let security_headers = SecurityHeaders::new(
+            31536000,
+            "no-referrer",
+            "script-src 'nonce-{nonce}' 'strict-dynamic'; frame-ancestors 'none'",
+            "script-src 'self'; frame-ancestors 'none'",
+        );
+        let router: Router = Router::new()
+            .route(
+                "/",
+                get(|nonce: CspNonce| async move {
+                    (
+                        Extension(nonce.clone()),
+                        Html(nonce.inject("<html><script src=\"/app.js\"></script></html>")),
+                    )
+                }),
+            )
+            .route(
+                "/untrusted",
+                get(|| async { Html("<html><script>alert(1)</script></html>") }),
+            )
+            .route("/api/test", get(|| async { Json("ok") }))
+            .layer(middleware::from_fn_with_state(
+                security_headers,
+                set_security_headers,
+            ));
+        TestServer::new(router).unwrap()
+    }
+
+    #[tokio::test]
+    async fn security_headers_document_nonce() {
+        let response = get_test_server().get("/").await;
+
+        let policy: String = response
+            .header(header::CONTENT_SECURITY_POLICY)
+            .to_str()
+            .unwrap()
+            .to_string();
+        let nonce: &str = policy
+            .strip_prefix("script-src 'nonce-")
+            .and_then(|policy| policy.split_once('\''))
+            .unwrap()
+            .0;
+        assert_eq!(
+            response.text(),
+            format!("<html><script nonce=\"{nonce}\" src=\"/app.js\"></script></html>")
+        );
+        assert_eq!(
+            response.header(header::STRICT_TRANSPORT_SECURITY),
+            "max-age=31536000"
+        );
+        assert_eq!(response.header(header::REFERRER_POLICY), "no-referrer");
+        assert_eq!(response.header(header::X_CONTENT_TYPE_OPTIONS), "nosniff");
+
+        // Every response gets its own nonce
+        let response = get_test_server().get("/").await;
+        assert_ne!(
+            response
+                .header(header::CONTENT_SECURITY_POLICY)
+                .to_str()
+                .unwrap(),
+            policy
+        );
+    }
+
+    /// Validates HTML that isn't from a trusted template gets the policy without a nonce, and isn't changed
+    #[tokio::test]
+    async fn security_headers_untrusted_document() {
+        let response = get_test_server().get("/untrusted").await;
+        assert_eq!(
+            response.header(header::CONTENT_SECURITY_POLICY),
+            "script-src 'self'; frame-ancestors 'none'"
+        );
+        assert_eq!(response.text(), "<html><script>alert(1)</script></html>");
+    }
+
+    #[tokio::test]
+    async fn security_headers_non_document() {
+        let response = get_test_server().get("/api/test").await;
+        assert_eq!(
+            response.header(header::CONTENT_SECURITY_POLICY),
+            NON_DOCUMENT_CONTENT_SECURITY_POLICY
+        );
+        assert_eq!(response.header(header::X_FRAME_OPTIONS), "DENY");
+    }
+}
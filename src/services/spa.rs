@@ -0,0 +1,161 @@
+//! Serves our Single Page Application's (SPA's) static files from its build directory (`spa_dist_dir`).
+//!
+//! Files are served precompressed when there's a `.br` or `.gz` copy next to them (see [`precompress`]), rather than
+//! being compressed on every request. The build's content-hashed files under `static/` never change, so browsers may
+//! cache them for good. Everything else must be revalidated, so a new deployment takes effect immediately. The rendered
+//! `index.html` goes further and isn't stored at all, since each response has its own nonce (see [`index`]).
+//!
+//! The SPA's bootstrap file is rendered with our runtime config injected into it (see [`index`]). With the `embed-spa`
+//! cargo feature, the SPA is served from the binary (see `embedded`) rather than from `spa_dist_dir` (see `files`). In
+//! dev mode, it's proxied to its dev server instead (see [`dev_proxy`]).
+pub mod dev_proxy;
+#[cfg(feature = "embed-spa")]
+pub mod embedded;
+#[cfg(not(feature = "embed-spa"))]
+pub mod files;
+pub mod index;
+pub mod precompress;
+
+use std::path::PathBuf;
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use index::{IndexDocument, RuntimeConfig};
+
+use super::{configs::AppConfig, security_headers};
+
+/// Where the build puts its content-hashed files, whose names change whenever their contents do
+const HASHED_FILES_PATH: &str = "/static/";
+
+const IMMUTABLE_CACHE_CONTROL: HeaderValue =
+    HeaderValue::from_static("public, max-age=31536000, immutable");
+const REVALIDATE_CACHE_CONTROL: HeaderValue = HeaderValue::from_static("no-cache");
+
+/// Returns the routes serving the SPA. Paths without a file get the SPA's bootstrap file (`spa_fallback_url`), so the
+/// SPA can route them itself. In dev mode, every request they get is proxied to the SPA's dev server.
+pub fn routes<S>(config: &AppConfig) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    if let Some(dev_server_url) = &config.spa_dev_server_url {
+        tracing::debug!("Proxying the SPA to its dev server at {dev_server_url}");
+        return dev_proxy::routes(dev_server_url, &RuntimeConfig::from_config(config));
+    }
+
+    let index_document: IndexDocument = IndexDocument::new(config);
+
+    #[cfg(feature = "embed-spa")]
+    let router: Router<S> = embedded::routes(index_document);
+    #[cfg(not(feature = "embed-spa"))]
+    let router: Router<S> = files::routes(config, index_document);
+
+    router.layer(middleware::from_fn(set_cache_control))
+}
+
+/// Writes the missing compressed copies of the SPA's files in the background, so startup isn't held up. Until they're
+/// written, files are compressed per request as before.
+pub fn init_precompressed_files(config: &AppConfig) {
+    let dist_dir: PathBuf = PathBuf::from(&config.spa_dist_dir);
+    tokio::task::spawn_blocking(move || match precompress::precompress_dir(&dist_dir) {
+        Ok(written) => tracing::debug!(
+            "Wrote {written} precompressed SPA files to {}",
+            dist_dir.display()
+        ),
+        Err(err) => tracing::warn!(
+            "Failed to precompress the SPA's files in {}: {err}",
+            dist_dir.display()
+        ),
+    });
+}
+
+/// Middleware that sets how long browsers and proxies may cache the SPA's files
+async fn set_cache_control(request: Request, next: Next) -> Response {
+    let is_hashed_file: bool = request.uri().path().starts_with(HASHED_FILES_PATH);
+    let mut response: Response = next.run(request).await;
+
+    // A missing hashed file gets the SPA's bootstrap file instead, which mustn't be cached for good
+    let is_found: bool =
+        response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED;
+    let cache_control: HeaderValue =
+        if is_hashed_file && is_found && !security_headers::is_html_document(response.headers()) {
+            IMMUTABLE_CACHE_CONTROL
+        } else {
+            REVALIDATE_CACHE_CONTROL
+        };
+
+    let headers = response.headers_mut();
+    // The SPA's bootstrap file sets its own when it has a nonce, which mustn't be cached at all
+    if !headers.contains_key(header::CACHE_CONTROL) {
+        headers.insert(header::CACHE_CONTROL, cache_control);
+    }
+    // The compression layer only adds this to responses it compresses itself
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    response
+}
+
+// Serves the SPA's files from a directory, so not with the embed-spa feature
+#[cfg(all(test, not(feature = "embed-spa")))]
+mod tests {
+
+    use super::*;
+    use crate::services::configs::AppConfig;
+    use axum_test::TestServer;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    #[tokio::test]
+    async fn spa_serve_files() {
+        let dir: PathBuf = std::env::temp_dir().join(format!("spa_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("static/js")).unwrap();
+        fs::write(
+            dir.join("static/js/main.abc123.js"),
+            "console.log('hello world');\n".repeat(100),
+        )
+        .unwrap();
+        fs::write(dir.join("index.html"), "<html><head></head></html>").unwrap();
+        fs::write(dir.join("manifest.json"), "{}").unwrap();
+        precompress::precompress_dir(&dir).unwrap();
+
+        let mut config: AppConfig = AppConfig::load().unwrap();
+        config.spa_dist_dir = dir.to_string_lossy().to_string();
+        config.spa_fallback_url = dir.join("index.html").to_string_lossy().to_string();
+        let server = TestServer::new(routes::<()>(&config)).unwrap();
+
+        let response = server
+            .get("/static/js/main.abc123.js")
+            .add_header(header::ACCEPT_ENCODING, HeaderValue::from_static("br"))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.header(header::CONTENT_ENCODING), "br");
+        assert_eq!(response.header(header::VARY), "accept-encoding");
+        assert_eq!(
+            response.header(header::CACHE_CONTROL),
+            IMMUTABLE_CACHE_CONTROL
+        );
+
+        // The SPA's bootstrap file is rendered however it's requested, including for a missing hashed file
+        for path in ["/", "/index.html", "/players/1", "/static/js/main.old.js"] {
+            let response = server.get(path).await;
+            assert!(response.text().contains("window.__APP_CONFIG__"), "{path}");
+        }
+
+        // Unhashed files and the SPA's bootstrap file must be revalidated, even when served for a missing hashed file
+        for path in ["/manifest.json", "/", "/static/js/main.old.js"] {
+            let response = server.get(path).await;
+            assert_eq!(
+                response.header(header::CACHE_CONTROL),
+                REVALIDATE_CACHE_CONTROL,
+                "{path}"
+            );
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
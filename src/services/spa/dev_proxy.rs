@@ -0,0 +1,372 @@
+//! Proxies the SPA to its dev server (e.g. `npm start`) in dev mode, turned on by setting `spa_dev_server_url`. Frontend
+//! changes then show up with live reload, while `/api` is still served by us on the same origin.
+//!
+//! Every request the SPA's routes get is proxied, including WebSocket upgrades for hot module replacement. HTML
+//! documents get our runtime config and the request's nonce injected like the built SPA's `index.html` does (see
+//! [`index`](super::index)), since the dev server is serving the same trusted source.
+//! The dev server's scripts need `'unsafe-eval'` in `security_content_security_policy` to run.
+use axum::{
+    body::{self, Body},
+    extract::Request,
+    http::{
+        header, uri::PathAndQuery, HeaderMap, HeaderName, HeaderValue, StatusCode, Uri, Version,
+    },
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+};
+
+use crate::{
+    api::errors::ApiError,
+    services::{
+        configs::ConfigReader,
+        security_headers::{self, CspNonce},
+    },
+};
+
+use super::index::{self, RuntimeConfig};
+
+/// Largest HTML document we'll buffer to inject our runtime config into
+const MAX_DOCUMENT_SIZE: usize = 2 * 1024 * 1024;
+
+/// Headers that only apply to a single connection, so aren't passed on
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Reads `spa_dev_server_url`, returning None if dev mode is off
+pub fn dev_server_url_from_config(reader: &mut ConfigReader) -> Option<Uri> {
+    let url: String = reader.get_optional_string("spa_dev_server_url")?;
+    match parse_dev_server_url(&url) {
+        Ok(url) => Some(url),
+        Err(err) => {
+            reader.add_problem("spa_dev_server_url", format!("\"{url}\" is invalid, {err}"));
+            None
+        }
+    }
+}
+
+fn parse_dev_server_url(url: &str) -> Result<Uri, String> {
+    let url: Uri = url.parse().map_err(|err| format!("{err}"))?;
+    if url.scheme_str() != Some("http") || url.authority().is_none() {
+        return Err(String::from(
+            "must be an http:// URL, e.g. \"http://localhost:3001\"",
+        ));
+    }
+    Ok(url)
+}
+
+/// Proxies requests to the SPA's dev server. Clones share the connection pool.
+#[derive(Clone)]
+struct DevServerProxy {
+    dev_server_url: Uri,
+    client: Client<HttpConnector, Body>,
+    /// Sets `window.__APP_CONFIG__`, see [`index`]
+    runtime_config_script: String,
+}
+
+/// Returns the routes proxying the SPA to its dev server
+pub fn routes<S>(dev_server_url: &Uri, runtime_config: &RuntimeConfig) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let proxy = DevServerProxy {
+        dev_server_url: dev_server_url.clone(),
+        client: Client::builder(TokioExecutor::new()).build_http(),
+        runtime_config_script: runtime_config.to_script(),
+    };
+    let proxy_request = move |request: Request| {
+        let proxy: DevServerProxy = proxy.clone();
+        async move { proxy.proxy(request).await }
+    };
+
+    Router::new()
+        .route("/", any(proxy_request.clone()))
+        .route("/*path", any(proxy_request))
+}
+
+impl DevServerProxy {
+    async fn proxy(self, mut request: Request) -> Response {
+        let nonce: Option<CspNonce> = request.extensions().get::<CspNonce>().cloned();
+        let is_upgrade: bool = request.headers().contains_key(header::UPGRADE);
+        let client_upgrade = is_upgrade.then(|| hyper::upgrade::on(&mut request));
+
+        let dev_server_request: Request = match self.to_dev_server_request(request, is_upgrade) {
+            Ok(dev_server_request) => dev_server_request,
+            Err(err) => return err.into_response(),
+        };
+        let mut response = match self.client.request(dev_server_request).await {
+            Ok(response) => response,
+            Err(err) => {
+                return ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    format!(
+                        "The SPA's dev server at {} didn't respond, is it running? {err}",
+                        self.dev_server_url
+                    ),
+                )
+                .into_response()
+            }
+        };
+
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            if let Some(client_upgrade) = client_upgrade {
+                let dev_server_upgrade = hyper::upgrade::on(&mut response);
+                tokio::spawn(async move {
+                    // Relays bytes both ways until either side closes
+                    match tokio::try_join!(client_upgrade, dev_server_upgrade) {
+                        Ok((client, dev_server)) => {
+                            let _ = tokio::io::copy_bidirectional(
+                                &mut TokioIo::new(client),
+                                &mut TokioIo::new(dev_server),
+                            )
+                            .await;
+                        }
+                        Err(err) => tracing::warn!("Failed to proxy an upgraded connection: {err}"),
+                    }
+                });
+            }
+            return response.map(Body::new);
+        }
+
+        remove_hop_by_hop_headers(response.headers_mut());
+        let response: Response = response.map(Body::new);
+        if security_headers::is_html_document(response.headers())
+            && !response.headers().contains_key(header::CONTENT_ENCODING)
+        {
+            return self.inject_runtime_config(response, nonce).await;
+        }
+        response
+    }
+
+    /// Rewrites the request for the dev server
+    fn to_dev_server_request(
+        &self,
+        request: Request,
+        is_upgrade: bool,
+    ) -> Result<Request, ApiError> {
+        let (mut parts, body) = request.into_parts();
+
+        let mut uri = self.dev_server_url.clone().into_parts();
+        uri.path_and_query = Some(
+            parts
+                .uri
+                .path_and_query()
+                .cloned()
+                .unwrap_or(PathAndQuery::from_static("/")),
+        );
+        parts.uri = Uri::from_parts(uri).map_err(|err| {
+            ApiError::internal(format!("Failed to build the dev server URL: {err}"))
+        })?;
+        // The dev server only speaks HTTP/1, whatever the client does
+        parts.version = Version::HTTP_11;
+
+        let upgrade: Option<HeaderValue> = parts.headers.get(header::UPGRADE).cloned();
+        remove_hop_by_hop_headers(&mut parts.headers);
+        if let (true, Some(upgrade)) = (is_upgrade, upgrade) {
+            parts
+                .headers
+                .insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            parts.headers.insert(header::UPGRADE, upgrade);
+        }
+        // Dev servers only accept their own host, and documents must stay uncompressed to inject our runtime config
+        if let Some(authority) = self.dev_server_url.authority() {
+            parts.headers.insert(
+                header::HOST,
+                HeaderValue::from_str(authority.as_str()).unwrap(),
+            );
+        }
+        parts.headers.remove(header::ACCEPT_ENCODING);
+
+        Ok(Request::from_parts(parts, body))
+    }
+
+    async fn inject_runtime_config(&self, response: Response, nonce: Option<CspNonce>) -> Response {
+        let (mut parts, body) = response.into_parts();
+        let document: body::Bytes = match body::to_bytes(body, MAX_DOCUMENT_SIZE).await {
+            Ok(document) => document,
+            Err(err) => {
+                return ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    format!("Failed to read the dev server's HTML document: {err}"),
+                )
+                .into_response()
+            }
+        };
+
+        parts.headers.remove(header::CONTENT_LENGTH);
+        let document: String = index::inject_script(
+            &String::from_utf8_lossy(&document),
+            &self.runtime_config_script,
+        );
+        let document: String = match nonce {
+            Some(nonce) => {
+                // Each response has its own nonce, so caches mustn't reuse it
+                parts
+                    .headers
+                    .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+                let document: String = nonce.inject(&document);
+                parts.extensions.insert(nonce);
+                document
+            }
+            None => document,
+        };
+        Response::from_parts(parts, Body::from(document))
+    }
+}
+
+/// Removes the headers that only apply to a single connection, including any the Connection header names
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let connection_headers: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect();
+    for name in connection_headers {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use axum::routing::get;
+    use pretty_assertions::assert_eq;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[test]
+    fn dev_proxy_parse_dev_server_url() {
+        assert_eq!(
+            parse_dev_server_url("http://localhost:3001").unwrap(),
+            Uri::from_static("http://localhost:3001")
+        );
+        assert!(parse_dev_server_url("https://localhost:3001").is_err());
+        assert!(parse_dev_server_url("localhost:3001").is_err());
+    }
+
+    /// Serves the router on a local port, returning its address
+    async fn serve(router: Router) -> String {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: String = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        addr
+    }
+
+    /// Validates requests, including upgraded connections, are proxied and HTML documents get our runtime config
+    #[tokio::test]
+    async fn dev_proxy_proxy_requests() {
+        // A dev server with a document, and an upgrade that echoes what it's sent
+        let dev_server: Router = Router::new()
+            .route(
+                "/",
+                get(|headers: HeaderMap| async move {
+                    let host: String = headers[header::HOST].to_str().unwrap().to_string();
+                    (
+                        [(header::CONTENT_TYPE, "text/html")],
+                        format!("<html><head></head><body>{host}</body></html>"),
+                    )
+                }),
+            )
+            .route(
+                "/ws",
+                get(|mut request: Request| async move {
+                    let upgrade = hyper::upgrade::on(&mut request);
+                    tokio::spawn(async move {
+                        let mut upgraded = TokioIo::new(upgrade.await.unwrap());
+                        let mut buffer = [0u8; 4];
+                        upgraded.read_exact(&mut buffer).await.unwrap();
+                        upgraded.write_all(&buffer).await.unwrap();
+                    });
+                    (
+                        StatusCode::SWITCHING_PROTOCOLS,
+                        [(header::CONNECTION, "upgrade"), (header::UPGRADE, "echo")],
+                    )
+                }),
+            );
+        let dev_server_addr: String = serve(dev_server).await;
+        let dev_server_url: Uri = format!("http://{dev_server_addr}").parse().unwrap();
+
+        let runtime_config = RuntimeConfig {
+            api_base_url: String::new(),
+            single_sign_on: false,
+            features: vec![],
+        };
+        let proxy_addr: String = serve(routes(&dev_server_url, &runtime_config)).await;
+
+        let document: String = reqwest::get(format!("http://{proxy_addr}/"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(
+            document,
+            format!(
+                "<html><head>{}</head><body>{dev_server_addr}</body></html>",
+                runtime_config.to_script()
+            )
+        );
+
+        let mut stream: TcpStream = TcpStream::connect(&proxy_addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response: Vec<u8> = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        let response: String = String::from_utf8(response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 101 Switching Protocols"),
+            "{response}"
+        );
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+
+    #[tokio::test]
+    async fn dev_proxy_dev_server_down() {
+        // Nothing listens on the port once the listener is dropped
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dev_server_url: Uri = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(listener);
+
+        let runtime_config = RuntimeConfig {
+            api_base_url: String::new(),
+            single_sign_on: false,
+            features: vec![],
+        };
+        let proxy_addr: String = serve(routes(&dev_server_url, &runtime_config)).await;
+
+        let response = reqwest::get(format!("http://{proxy_addr}/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+}
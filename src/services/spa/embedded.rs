@@ -0,0 +1,173 @@
+//! Serves the SPA's build and our sample page from copies embedded in the binary, with the `embed-spa` cargo feature.
+//! The binary can then be deployed on its own, rather than along with `spa_dist_dir` and `sample_page.html` (which
+//! [`static_mounts`](crate::services::static_mounts) then serve from the binary). The SPA must be built
+//! (`npm run build`) before the app server.
+//!
+//! Without the feature they're read from the filesystem instead, so changes show up without rebuilding the binary.
+use std::{borrow::Cow, path::Path};
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use rust_embed::RustEmbed;
+use sha2::{Digest, Sha256};
+
+use crate::services::security_headers::CspNonce;
+
+use super::index::IndexDocument;
+
+/// The SPA's build directory (the default `spa_dist_dir`), embedded at compile time
+#[derive(RustEmbed)]
+#[folder = "my-react-ts-app/build"]
+struct SpaFiles;
+
+const SAMPLE_PAGE: &[u8] = include_bytes!("../../../sample_page.html");
+
+/// Files outside the SPA embedded in the binary, by their path relative to the working directory, with their mimetype
+const EMBEDDED_FILES: &[(&str, &str, &[u8])] = &[("sample_page.html", "text/html", SAMPLE_PAGE)];
+
+/// The SPA's bootstrap file, relative to its build directory
+const INDEX_FILE: &str = "index.html";
+
+/// Returns the embedded copy of the SPA's bootstrap file
+pub fn index_template() -> Option<Cow<'static, [u8]>> {
+    SpaFiles::get(INDEX_FILE).map(|file| file.data)
+}
+
+/// Returns the routes serving the SPA's embedded files. Paths without a file get the SPA's bootstrap file, so the SPA
+/// can route them itself.
+pub fn routes<S>(index_document: IndexDocument) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let serve_index_document = {
+        let index_document: IndexDocument = index_document.clone();
+        move |nonce: Option<CspNonce>| index_document.clone().respond(nonce)
+    };
+
+    Router::new()
+        .route("/", get(serve_index_document.clone()))
+        .route("/index.html", get(serve_index_document))
+        .route(
+            "/*path",
+            get(
+                move |uri: Uri, headers: HeaderMap, nonce: Option<CspNonce>| async move {
+                    match SpaFiles::get(&file_path(&uri)) {
+                        Some(file) => respond(
+                            &headers,
+                            file.data,
+                            file.metadata.mimetype(),
+                            file.metadata.sha256_hash(),
+                        ),
+                        // Like the filesystem's not found service, the bootstrap file is served as a 404
+                        None => {
+                            let mut response: Response = index_document.respond(nonce).await;
+                            *response.status_mut() = StatusCode::NOT_FOUND;
+                            response
+                        }
+                    }
+                },
+            ),
+        )
+}
+
+/// Returns the route serving the embedded copy of a static mount's file, or None if it isn't embedded
+pub fn file_routes<S>(path: &str, source: &Path) -> Option<Router<S>>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let (_, mimetype, data) = EMBEDDED_FILES
+        .iter()
+        .find(|(name, _, _)| Path::new(name) == source)?;
+    let hash: [u8; 32] = Sha256::digest(data).into();
+    Some(Router::new().route(
+        path,
+        get(move |headers: HeaderMap| async move {
+            respond(&headers, Cow::Borrowed(*data), mimetype, hash)
+        }),
+    ))
+}
+
+/// The embedded file's path for the request, e.g. "/static/js/main.js" is "static/js/main.js". Directories get their
+/// index.html, like ServeDir.
+fn file_path(uri: &Uri) -> String {
+    let path: &str = uri.path().trim_start_matches('/');
+    if path.is_empty() || path.ends_with('/') {
+        format!("{path}{INDEX_FILE}")
+    } else {
+        path.to_string()
+    }
+}
+
+/// Responds with an embedded file, or a 304 when the client's copy has the same ETag
+fn respond(
+    request_headers: &HeaderMap,
+    data: Cow<'static, [u8]>,
+    mimetype: &str,
+    sha256_hash: [u8; 32],
+) -> Response {
+    let etag = HeaderValue::from_str(&format!("\"{}\"", hex::encode(sha256_hash)))
+        .expect("a hex encoded hash is a valid header value");
+
+    let is_not_modified: bool = request_headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().trim_start_matches("W/"))
+        .any(|value| value == "*" || value.as_bytes() == etag.as_bytes());
+    if is_not_modified {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+
+    let content_type = HeaderValue::from_str(mimetype)
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    (
+        [(header::CONTENT_TYPE, content_type), (header::ETAG, etag)],
+        data,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use axum_test::TestServer;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn embedded_file_path() {
+        assert_eq!(
+            file_path(&Uri::from_static("/static/js/main.js")),
+            "static/js/main.js"
+        );
+        assert_eq!(file_path(&Uri::from_static("/")), "index.html");
+        assert_eq!(file_path(&Uri::from_static("/docs/")), "docs/index.html");
+    }
+
+    #[tokio::test]
+    async fn embedded_serve_sample_page() {
+        assert!(file_routes::<()>("/other-page", Path::new("missing.html")).is_none());
+        let server = TestServer::new(
+            file_routes::<()>("/other-page", Path::new("sample_page.html")).unwrap(),
+        )
+        .unwrap();
+
+        let response = server.get("/other-page").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.header(header::CONTENT_TYPE), "text/html");
+        assert_eq!(response.as_bytes().as_ref(), SAMPLE_PAGE);
+
+        let etag: HeaderValue = response.header(header::ETAG);
+        let response = server
+            .get("/other-page")
+            .add_header(header::IF_NONE_MATCH, etag.clone())
+            .await;
+        assert_eq!(response.status_code(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.header(header::ETAG), etag);
+    }
+}
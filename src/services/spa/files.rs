@@ -0,0 +1,39 @@
+//! Serves the SPA's build from `spa_dist_dir`, when it isn't embedded in the binary (see the `embed-spa` cargo
+//! feature). Changes to it show up without rebuilding the binary.
+use std::convert::Infallible;
+
+use axum::{extract::Request, routing::get, Router};
+use tower_http::services::ServeDir;
+
+use crate::services::{configs::AppConfig, security_headers::CspNonce};
+
+use super::index::IndexDocument;
+
+/// Returns the routes serving the SPA's files. Paths without a file get the SPA's bootstrap file, so the SPA can route
+/// them itself.
+pub fn routes<S>(config: &AppConfig, index_document: IndexDocument) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let serve_index_document = {
+        let index_document: IndexDocument = index_document.clone();
+        move |nonce: Option<CspNonce>| index_document.clone().respond(nonce)
+    };
+    let not_found_service = tower::service_fn(move |request: Request| {
+        let index_document: IndexDocument = index_document.clone();
+        let nonce: Option<CspNonce> = request.extensions().get::<CspNonce>().cloned();
+        async move { Ok::<_, Infallible>(index_document.respond(nonce).await) }
+    });
+
+    Router::new()
+        // Requested directly, the bootstrap file must be rendered rather than served as is
+        .route("/", get(serve_index_document.clone()))
+        .route("/index.html", get(serve_index_document))
+        .route_service(
+            "/*path",
+            ServeDir::new(&config.spa_dist_dir)
+                .precompressed_br()
+                .precompressed_gzip()
+                .not_found_service(not_found_service),
+        )
+}
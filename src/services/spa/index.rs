@@ -0,0 +1,214 @@
+//! Renders the SPA's bootstrap file (`spa_fallback_url`, i.e. `index.html`) with our runtime config injected as
+//! `window.__APP_CONFIG__`, so one build of the SPA can be promoted across environments rather than having its config
+//! baked in at `npm run build`.
+//!
+//! Only what's in [`RuntimeConfig`] is exposed, since anything in the document is public. The rendered document is
+//! cached in memory, and rendered again once the file changes (e.g. after a new build). With the `embed-spa` feature,
+//! the embedded copy is rendered instead. Each response then gets the request's nonce added to the document's
+//! scripts, the injected one included, as the one trusted template (see
+//! [`security_headers`](crate::services::security_headers)).
+use std::{
+    io,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use axum::{
+    body::Bytes,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::Serialize;
+
+use crate::services::{configs::AppConfig, security_headers::CspNonce};
+
+const HTML_CONTENT_TYPE: HeaderValue = HeaderValue::from_static("text/html; charset=utf-8");
+
+/// The subset of our config the SPA gets, as `window.__APP_CONFIG__`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuntimeConfig {
+    /// Where the SPA sends API requests. Empty for the origin it's served from.
+    pub api_base_url: String,
+    /// Whether users can log in with single sign-on
+    pub single_sign_on: bool,
+    /// Names of the SPA features turned on
+    pub features: Vec<String>,
+}
+
+impl RuntimeConfig {
+    pub fn from_config(config: &AppConfig) -> Self {
+        RuntimeConfig {
+            api_base_url: config.spa_api_base_url.clone(),
+            single_sign_on: config.oidc.is_some(),
+            features: config.spa_features.clone(),
+        }
+    }
+
+    /// The `<script>` that sets `window.__APP_CONFIG__`
+    pub fn to_script(&self) -> String {
+        let json: String = serde_json::to_string(self).expect("RuntimeConfig serializes to JSON");
+        // Escaped so a value can't close the script tag or open an HTML comment
+        let json: String = json
+            .replace('<', "\\u003c")
+            .replace('>', "\\u003e")
+            .replace('&', "\\u0026");
+        format!("<script>window.__APP_CONFIG__ = {json};</script>")
+    }
+}
+
+/// The SPA's bootstrap file, rendered with our runtime config. Clones share the cached document.
+#[derive(Clone)]
+pub struct IndexDocument {
+    template: Template,
+    script: Arc<str>,
+    cache: Arc<RwLock<Option<Rendered>>>,
+}
+
+/// A rendered document
+struct Rendered {
+    /// When the file it was rendered from was last modified, None for the embedded copy
+    modified: Option<SystemTime>,
+    document: Bytes,
+}
+
+/// Where the document is rendered from
+#[derive(Clone)]
+enum Template {
+    /// The file at `spa_fallback_url`
+    #[cfg(not(feature = "embed-spa"))]
+    File(std::path::PathBuf),
+    /// The copy embedded in the binary
+    #[cfg(feature = "embed-spa")]
+    Embedded,
+}
+
+impl IndexDocument {
+    pub fn new(config: &AppConfig) -> Self {
+        #[cfg(feature = "embed-spa")]
+        let template = Template::Embedded;
+        #[cfg(not(feature = "embed-spa"))]
+        let template = Template::File(std::path::PathBuf::from(&config.spa_fallback_url));
+
+        IndexDocument {
+            template,
+            script: RuntimeConfig::from_config(config).to_script().into(),
+            cache: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Responds with the rendered document, with the nonce added to its tags. Without one (i.e. without the security
+    /// headers middleware), its scripts aren't allowed to run.
+    pub async fn respond(self, nonce: Option<CspNonce>) -> Response {
+        match self.render().await {
+            Ok(document) => match nonce {
+                // Each response has its own nonce, so caches mustn't reuse it
+                Some(nonce) => (
+                    [
+                        (header::CONTENT_TYPE, HTML_CONTENT_TYPE),
+                        (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+                    ],
+                    Extension(nonce.clone()),
+                    nonce.inject(&String::from_utf8_lossy(&document)),
+                )
+                    .into_response(),
+                None => ([(header::CONTENT_TYPE, HTML_CONTENT_TYPE)], document).into_response(),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                StatusCode::NOT_FOUND.into_response()
+            }
+            Err(err) => {
+                tracing::error!("Failed to render the SPA's bootstrap file: {err}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    /// Returns the cached document, rendering it again first if the file has changed since
+    async fn render(&self) -> io::Result<Bytes> {
+        let modified: Option<SystemTime> = match &self.template {
+            #[cfg(not(feature = "embed-spa"))]
+            Template::File(path) => Some(tokio::fs::metadata(path).await?.modified()?),
+            #[cfg(feature = "embed-spa")]
+            Template::Embedded => None,
+        };
+        if let Some(rendered) = self.cache.read().unwrap().as_ref() {
+            if rendered.modified == modified {
+                return Ok(rendered.document.clone());
+            }
+        }
+
+        let template: String = match &self.template {
+            #[cfg(not(feature = "embed-spa"))]
+            Template::File(path) => tokio::fs::read_to_string(path).await?,
+            #[cfg(feature = "embed-spa")]
+            Template::Embedded => super::embedded::index_template()
+                .map(|template| String::from_utf8_lossy(&template).into_owned())
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "index.html isn't embedded")
+                })?,
+        };
+        let document = Bytes::from(inject_script(&template, &self.script));
+        *self.cache.write().unwrap() = Some(Rendered {
+            modified,
+            document: document.clone(),
+        });
+        Ok(document)
+    }
+}
+
+/// Inserts the script at the start of the document's `<head>`, so it runs before the SPA's own scripts
+pub fn inject_script(template: &str, script: &str) -> String {
+    let insert_at: usize = template
+        .to_ascii_lowercase()
+        .find("<head")
+        .and_then(|head_start| {
+            template[head_start..]
+                .find('>')
+                .map(|head_end| head_start + head_end + 1)
+        })
+        .unwrap_or(0);
+
+    let mut document = String::with_capacity(template.len() + script.len());
+    document.push_str(&template[..insert_at]);
+    document.push_str(script);
+    document.push_str(&template[insert_at..]);
+    document
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn index_runtime_config_script() {
+        let runtime_config = RuntimeConfig {
+            api_base_url: String::from("https://api.example.com"),
+            single_sign_on: true,
+            features: vec![String::from("</script><script>alert(1)")],
+        };
+        assert_eq!(
+            runtime_config.to_script(),
+            "<script>window.__APP_CONFIG__ = {\"apiBaseUrl\":\"https://api.example.com\",\"singleSignOn\":true,\
+             \"features\":[\"\\u003c/script\\u003e\\u003cscript\\u003ealert(1)\"]};</script>"
+        );
+    }
+
+    #[test]
+    fn index_inject_script() {
+        assert_eq!(
+            inject_script(
+                "<!doctype html><HTML><Head lang=\"en\"><script src=\"/static/js/main.js\"></script></head></html>",
+                "<script>config</script>"
+            ),
+            "<!doctype html><HTML><Head lang=\"en\"><script>config</script><script src=\"/static/js/main.js\"></script></head></html>"
+        );
+        assert_eq!(
+            inject_script("<div id=\"root\"></div>", "<script>config</script>"),
+            "<script>config</script><div id=\"root\"></div>"
+        );
+    }
+}
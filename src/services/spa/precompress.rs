@@ -0,0 +1,186 @@
+//! Writes Brotli (`.br`) and gzip (`.gz`) copies next to the SPA's files, so they're served precompressed rather than
+//! compressed on every request. Copies are only written when missing or older than their file, so this is cheap after
+//! the first run for a build.
+//!
+//! HTML documents are skipped, since nonces are injected into them per response (see
+//! [`security_headers`](crate::services::security_headers)) and a precompressed document can't be rewritten.
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+/// Extensions of files worth compressing. Images and fonts are already compressed.
+const COMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "js", "mjs", "css", "json", "map", "svg", "txt", "xml", "wasm", "ico",
+];
+
+/// Files smaller than this aren't worth compressing
+const MIN_SIZE_BYTES: u64 = 1024;
+
+const BROTLI_QUALITY: u32 = 11;
+const BROTLI_WINDOW_BITS: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// A compressed copy of a file, named after the file with this extension added
+struct Sidecar {
+    extension: &'static str,
+    compress: fn(&[u8]) -> io::Result<Vec<u8>>,
+}
+
+const SIDECARS: &[Sidecar] = &[
+    Sidecar {
+        extension: "br",
+        compress: compress_brotli,
+    },
+    Sidecar {
+        extension: "gz",
+        compress: compress_gzip,
+    },
+];
+
+/// Writes the missing or outdated compressed copies of the files in the directory and its subdirectories, returning
+/// how many were written
+pub fn precompress_dir(dir: &Path) -> io::Result<usize> {
+    let mut written: usize = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry: fs::DirEntry = entry?;
+        let path: PathBuf = entry.path();
+        let file_type: fs::FileType = entry.file_type()?;
+
+        if file_type.is_dir() {
+            written += precompress_dir(&path)?;
+        } else if file_type.is_file() && is_compressible(&path, entry.metadata()?.len()) {
+            written += precompress_file(&path)?;
+        } else if is_html_sidecar(&path) {
+            tracing::warn!(
+                "{} is served instead of the HTML document it compresses, so nonces can't be injected into it. Remove it.",
+                path.display()
+            );
+        }
+    }
+    Ok(written)
+}
+
+fn is_compressible(path: &Path, size: u64) -> bool {
+    size >= MIN_SIZE_BYTES
+        && path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| COMPRESSIBLE_EXTENSIONS.contains(&extension))
+}
+
+fn is_html_sidecar(path: &Path) -> bool {
+    let name: String = path.to_string_lossy().to_lowercase();
+    SIDECARS
+        .iter()
+        .any(|sidecar| name.ends_with(&format!(".html.{}", sidecar.extension)))
+}
+
+/// Writes the file's compressed copies, returning how many were written. Copies that wouldn't be smaller aren't.
+fn precompress_file(path: &Path) -> io::Result<usize> {
+    let modified: SystemTime = fs::metadata(path)?.modified()?;
+    let mut contents: Option<Vec<u8>> = None;
+    let mut written: usize = 0;
+
+    for sidecar in SIDECARS {
+        let sidecar_path: PathBuf = add_extension(path, sidecar.extension);
+        let up_to_date: bool = fs::metadata(&sidecar_path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|sidecar_modified| sidecar_modified >= modified);
+        if up_to_date {
+            continue;
+        }
+
+        if contents.is_none() {
+            contents = Some(fs::read(path)?);
+        }
+        let contents: &[u8] = contents.as_deref().unwrap();
+        let compressed: Vec<u8> = (sidecar.compress)(contents)?;
+        if compressed.len() >= contents.len() {
+            continue;
+        }
+
+        // Written under a temporary name and then renamed, so a partly written copy is never served
+        let temp_path: PathBuf = add_extension(&sidecar_path, "tmp");
+        fs::write(&temp_path, compressed)?;
+        fs::rename(&temp_path, &sidecar_path)?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Appends an extension to the path, e.g. "main.js" with "br" is "main.js.br"
+fn add_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+fn compress_brotli(contents: &[u8]) -> io::Result<Vec<u8>> {
+    let mut writer = brotli::CompressorWriter::new(
+        Vec::new(),
+        BROTLI_BUFFER_SIZE,
+        BROTLI_QUALITY,
+        BROTLI_WINDOW_BITS,
+    );
+    writer.write_all(contents)?;
+    Ok(writer.into_inner())
+}
+
+fn compress_gzip(contents: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+
+    #[test]
+    fn precompress_spa_files() {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("precompress_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("static/js")).unwrap();
+        let script: String = "console.log('hello world');\n".repeat(100);
+        fs::write(dir.join("static/js/main.abc123.js"), &script).unwrap();
+        fs::write(dir.join("static/js/tiny.js"), "1").unwrap();
+        fs::write(dir.join("index.html"), "<html></html>\n".repeat(100)).unwrap();
+
+        assert_eq!(precompress_dir(&dir).unwrap(), 2);
+
+        let mut decompressed = String::new();
+        brotli::Decompressor::new(
+            fs::File::open(dir.join("static/js/main.abc123.js.br")).unwrap(),
+            BROTLI_BUFFER_SIZE,
+        )
+        .read_to_string(&mut decompressed)
+        .unwrap();
+        assert_eq!(decompressed, script);
+
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(
+            fs::File::open(dir.join("static/js/main.abc123.js.gz")).unwrap(),
+        )
+        .read_to_string(&mut decompressed)
+        .unwrap();
+        assert_eq!(decompressed, script);
+
+        // Too small to be worth it, and HTML has nonces injected per response
+        assert!(!dir.join("static/js/tiny.js.br").exists());
+        assert!(!dir.join("index.html.br").exists());
+
+        // Copies are only written again once they're outdated
+        assert_eq!(precompress_dir(&dir).unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
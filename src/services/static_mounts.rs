@@ -0,0 +1,466 @@
+//! Serves static files next to the SPA (e.g. docs, a status page or marketing micro-sites), as mounted in the
+//! `static_mounts` config value. Each mount maps a URL path to a file or directory:
+//!
+//! ```toml
+//! static_mounts = [
+//!     { path = "/docs", source = "docs/build", cache_control = "public, max-age=300", directory_listing = true, fallback = "404.html" },
+//! ]
+//! ```
+//!
+//! - `cache_control` is the Cache-Control of the files served, HTML documents included, "no-cache" by default.
+//! - `directory_listing` lists a directory's files when it has no index.html. Directories only, off by default.
+//! - `fallback` is a file in the directory served with a 404 for paths without a file. Directories only.
+//!
+//! Mounts are validated when the config is loaded, so they can't shadow our own routes (e.g. `/api`) or each other.
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    Router,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use tower::{util::BoxCloneService, ServiceExt};
+use tower_http::{
+    services::{ServeDir, ServeFile},
+    set_status::SetStatus,
+};
+
+use super::{app_server, configs::ConfigReader, health, metrics};
+
+/// Paths our own routes are served at, which mounts can't be at or under
+const RESERVED_PATHS: &[&str] = &[
+    app_server::API_BASE_PATH,
+    health::LIVENESS_PATH,
+    health::READINESS_PATH,
+    metrics::METRICS_PATH,
+    // The SPA's bootstrap file, the SPA itself is at every other path
+    "/index.html",
+];
+
+const DEFAULT_CACHE_CONTROL: &str = "no-cache";
+
+/// Characters escaped in the links of directory listings
+const LINK_ESCAPES: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A file or directory served at a URL path, from the `static_mounts` config value
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaticMount {
+    /// e.g. "/docs"
+    pub path: String,
+    pub source: PathBuf,
+    pub cache_control: HeaderValue,
+    pub directory_listing: bool,
+    /// Relative to the source directory
+    pub fallback: Option<PathBuf>,
+}
+
+/// A mount as it's written in the config
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StaticMountSettings {
+    path: String,
+    source: String,
+    cache_control: Option<String>,
+    #[serde(default)]
+    directory_listing: bool,
+    fallback: Option<String>,
+}
+
+impl StaticMount {
+    /// Reads and validates the mounts in the `static_mounts` config value
+    pub fn from_config(reader: &mut ConfigReader) -> Vec<Self> {
+        let settings: Vec<StaticMountSettings> = reader.get_table_list("static_mounts");
+
+        let mut mounts: Vec<StaticMount> = Vec::with_capacity(settings.len());
+        for settings in settings {
+            match StaticMount::try_from_settings(settings, &mounts) {
+                Ok(mount) => mounts.push(mount),
+                Err(err) => reader.add_problem("static_mounts", err),
+            }
+        }
+        mounts
+    }
+
+    fn try_from_settings(
+        settings: StaticMountSettings,
+        mounts: &[StaticMount],
+    ) -> Result<Self, String> {
+        let path: String = settings.path;
+        validate_path(&path, mounts)?;
+
+        let cache_control: String = settings
+            .cache_control
+            .unwrap_or_else(|| String::from(DEFAULT_CACHE_CONTROL));
+        let cache_control: HeaderValue = HeaderValue::from_str(&cache_control).map_err(|_| {
+            format!("{path}: cache_control \"{cache_control}\" isn't a valid header value")
+        })?;
+
+        // Missing sources are only warned about when serving, since they may be deployed separately
+        let source = PathBuf::from(settings.source);
+        let fallback: Option<PathBuf> = settings
+            .fallback
+            .filter(|fallback| !fallback.trim().is_empty())
+            .map(PathBuf::from);
+        if source.is_file() && (settings.directory_listing || fallback.is_some()) {
+            return Err(format!(
+                "{path}: directory_listing and fallback are only for directories, {} is a file",
+                source.display()
+            ));
+        }
+
+        Ok(StaticMount {
+            path,
+            source,
+            cache_control,
+            directory_listing: settings.directory_listing,
+            fallback,
+        })
+    }
+}
+
+/// Checks the mount's path is a plain path that doesn't shadow our own routes or other mounts
+fn validate_path(path: &str, mounts: &[StaticMount]) -> Result<(), String> {
+    let segments: Vec<&str> = path
+        .strip_prefix('/')
+        .unwrap_or_default()
+        .split('/')
+        .collect();
+    if !path.starts_with('/') || path == "/" || segments.iter().any(|segment| segment.is_empty()) {
+        return Err(format!(
+            "{path}: path must be like \"/docs\", with no trailing slash. The SPA is served at \"/\"."
+        ));
+    }
+    if path.contains([':', '*', '{', '}']) {
+        return Err(format!(
+            "{path}: path can't have route parameters or wildcards"
+        ));
+    }
+
+    let overlaps = |other: &str| {
+        path == other
+            || path.starts_with(&format!("{other}/"))
+            || other.starts_with(&format!("{path}/"))
+    };
+    if let Some(reserved) = RESERVED_PATHS.iter().find(|reserved| overlaps(reserved)) {
+        return Err(format!(
+            "{path}: path conflicts with our own routes at {reserved}"
+        ));
+    }
+    if let Some(mount) = mounts.iter().find(|mount| overlaps(&mount.path)) {
+        return Err(format!(
+            "{path}: path conflicts with the mount at {}",
+            mount.path
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the routes serving the mounts
+pub fn routes<S>(mounts: &[StaticMount]) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let mut router: Router<S> = Router::new();
+    for mount in mounts {
+        router = router.merge(mount_routes(mount));
+    }
+    router
+}
+
+fn mount_routes<S>(mount: &StaticMount) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let set_cache_control =
+        middleware::from_fn_with_state(mount.cache_control.clone(), set_cache_control);
+
+    #[cfg(feature = "embed-spa")]
+    if let Some(router) = super::spa::embedded::file_routes(&mount.path, &mount.source) {
+        return router.layer(set_cache_control);
+    }
+
+    if !mount.source.exists() {
+        tracing::warn!(
+            "{} is mounted at {}, but doesn't exist",
+            mount.source.display(),
+            mount.path
+        );
+    }
+    if !mount.source.is_dir() {
+        return Router::new()
+            .nest_service(&mount.path, ServeFile::new(&mount.source))
+            .layer(set_cache_control);
+    }
+
+    // Without a fallback, paths without a file get ServeDir's usual empty 404
+    let not_found_service: NotFoundService = match &mount.fallback {
+        Some(fallback) => BoxCloneService::new(ServiceExt::<Request>::map_response(
+            ServeFile::new(mount.source.join(fallback)),
+            IntoResponse::into_response,
+        )),
+        None => BoxCloneService::new(tower::service_fn(|_: Request| async {
+            Ok(StatusCode::NOT_FOUND.into_response())
+        })),
+    };
+    let directory = DirectoryMount {
+        source: mount.source.clone(),
+        directory_listing: mount.directory_listing,
+        serve_dir: ServeDir::new(&mount.source).not_found_service(not_found_service),
+    };
+    Router::new()
+        .nest_service(
+            &mount.path,
+            tower::service_fn(move |request: Request| directory.clone().serve(request)),
+        )
+        .layer(set_cache_control)
+}
+
+type NotFoundService = BoxCloneService<Request, Response, Infallible>;
+
+/// Serves a directory mount's files, and its listings when they're turned on
+#[derive(Clone)]
+struct DirectoryMount {
+    source: PathBuf,
+    directory_listing: bool,
+    serve_dir: ServeDir<SetStatus<NotFoundService>>,
+}
+
+impl DirectoryMount {
+    async fn serve(self, request: Request) -> Result<Response, Infallible> {
+        // Nested, the request's path is relative to the mount, e.g. "/guides/" for "/docs/guides/"
+        let original_path: String = request
+            .extensions()
+            .get::<OriginalUri>()
+            .map_or(request.uri().path(), |original_uri| original_uri.path())
+            .to_string();
+
+        if let Some(relative_path) = to_relative_path(request.uri().path()) {
+            let dir: PathBuf = self.source.join(&relative_path);
+            if tokio::fs::metadata(&dir)
+                .await
+                .is_ok_and(|metadata| metadata.is_dir())
+            {
+                // ServeDir would redirect to the path without the mount's, so it's done here. The trailing slash
+                // makes links relative to the directory.
+                if !original_path.ends_with('/') {
+                    let location: String = match request.uri().query() {
+                        Some(query) => format!("{original_path}/?{query}"),
+                        None => format!("{original_path}/"),
+                    };
+                    return Ok(Redirect::temporary(&location).into_response());
+                }
+                if self.directory_listing && !dir.join("index.html").is_file() {
+                    if let Some(listing) =
+                        list_directory(&dir, &original_path, &relative_path).await
+                    {
+                        return Ok(listing);
+                    }
+                }
+            }
+        }
+
+        self.serve_dir
+            .oneshot(request)
+            .await
+            .map(IntoResponse::into_response)
+    }
+}
+
+/// Middleware that sets the mount's Cache-Control on the files it serves. Errors and fallbacks must be revalidated.
+async fn set_cache_control(
+    State(cache_control): State<HeaderValue>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response: Response = next.run(request).await;
+    let is_found: bool =
+        response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED;
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        if is_found {
+            cache_control
+        } else {
+            HeaderValue::from_static(DEFAULT_CACHE_CONTROL)
+        },
+    );
+    response
+}
+
+/// Renders a listing of the directory's files, or None if it can't be read
+async fn list_directory(dir: &Path, request_path: &str, relative_path: &Path) -> Option<Response> {
+    let mut names: Vec<String> = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name: String = entry.file_name().to_string_lossy().to_string();
+        let is_dir: bool = entry
+            .file_type()
+            .await
+            .is_ok_and(|file_type| file_type.is_dir());
+        names.push(if is_dir { format!("{name}/") } else { name });
+    }
+    names.sort();
+
+    let title: String = escape_html(&format!("Index of {request_path}"));
+    let mut document: String = format!(
+        "<!DOCTYPE html><html><head><title>{title}</title></head><body><h1>{title}</h1><ul>"
+    );
+    if relative_path.components().next().is_some() {
+        document.push_str("<li><a href=\"../\">../</a></li>");
+    }
+    for name in names {
+        let link: String = match name.strip_suffix('/') {
+            Some(dir_name) => format!("{}/", utf8_percent_encode(dir_name, LINK_ESCAPES)),
+            None => utf8_percent_encode(&name, LINK_ESCAPES).to_string(),
+        };
+        let _ = write!(
+            document,
+            "<li><a href=\"{link}\">{}</a></li>",
+            escape_html(&name)
+        );
+    }
+    document.push_str("</ul></body></html>");
+    Some(Html(document).into_response())
+}
+
+/// Decodes the request's path, relative to the mount. Returns None for paths that would leave it, e.g. with "..".
+fn to_relative_path(request_path: &str) -> Option<PathBuf> {
+    let decoded: String = percent_decode_str(request_path)
+        .decode_utf8()
+        .ok()?
+        .to_string();
+    let mut relative_path = PathBuf::new();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(segment) => relative_path.push(segment),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(relative_path)
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use axum_test::TestServer;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    fn settings(path: &str, source: &str) -> StaticMountSettings {
+        StaticMountSettings {
+            path: String::from(path),
+            source: String::from(source),
+            cache_control: None,
+            directory_listing: false,
+            fallback: None,
+        }
+    }
+
+    #[test]
+    fn static_mounts_validate() {
+        let mounts: Vec<StaticMount> =
+            vec![StaticMount::try_from_settings(settings("/docs", "docs"), &[]).unwrap()];
+        assert_eq!(mounts[0].cache_control, DEFAULT_CACHE_CONTROL);
+
+        for path in [
+            "/",
+            "docs",
+            "/docs/",
+            "/docs//api",
+            "/docs/:page",
+            "/api",
+            "/api/docs",
+            "/healthz",
+            "/docs/v2",
+        ] {
+            assert!(
+                StaticMount::try_from_settings(settings(path, "docs"), &mounts).is_err(),
+                "{path}"
+            );
+        }
+        assert!(StaticMount::try_from_settings(settings("/docs-v2", "docs"), &mounts).is_ok());
+
+        let mut file_with_listing: StaticMountSettings = settings("/page", "sample_page.html");
+        file_with_listing.directory_listing = true;
+        assert!(StaticMount::try_from_settings(file_with_listing, &[]).is_err());
+    }
+
+    #[test]
+    fn static_mounts_to_relative_path() {
+        assert_eq!(
+            to_relative_path("/guides/my%20guide/").unwrap(),
+            PathBuf::from("guides/my guide")
+        );
+        assert!(to_relative_path("/guides/../../secrets/").is_none());
+        assert!(to_relative_path("/%2E%2E/").is_none());
+    }
+
+    #[tokio::test]
+    async fn static_mounts_serve() {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("static_mounts_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("guides")).unwrap();
+        fs::write(dir.join("guides/intro.txt"), "intro").unwrap();
+        fs::write(dir.join("404.html"), "<html>not found</html>").unwrap();
+
+        let mut docs: StaticMountSettings = settings("/docs", dir.to_str().unwrap());
+        docs.cache_control = Some(String::from("public, max-age=300"));
+        docs.directory_listing = true;
+        docs.fallback = Some(String::from("404.html"));
+        let mounts: Vec<StaticMount> = vec![
+            StaticMount::try_from_settings(docs, &[]).unwrap(),
+            StaticMount::try_from_settings(settings("/other-page", "sample_page.html"), &[])
+                .unwrap(),
+        ];
+        let server = TestServer::new(routes::<()>(&mounts)).unwrap();
+
+        let response = server.get("/docs/guides/intro.txt").await;
+        assert_eq!(response.text(), "intro");
+        assert_eq!(
+            response.header(header::CACHE_CONTROL),
+            "public, max-age=300"
+        );
+
+        let response = server.get("/docs/guides").await;
+        assert_eq!(response.status_code(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(response.header(header::LOCATION), "/docs/guides/");
+
+        let response = server.get("/docs/guides/").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert!(response
+            .text()
+            .contains("<a href=\"intro.txt\">intro.txt</a>"));
+
+        let response = server.get("/docs/missing.txt").await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(response.text(), "<html>not found</html>");
+        assert_eq!(response.header(header::CACHE_CONTROL), "no-cache");
+
+        let response = server.get("/other-page").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.header(header::CACHE_CONTROL), "no-cache");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
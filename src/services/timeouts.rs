@@ -0,0 +1,118 @@
+//! Provides request timeouts, set per group of routes in the request_timeout_* config values since they do very
+//! different amounts of work, e.g. searches call Search while serving the SPA only reads files.
+//!
+//! The [`limit`] middleware answers a request that runs out of time with a 504, and a request still in flight at the
+//! app server's shutdown deadline (see [`serve`](super::app_server::serve)) with a 503, both in our usual JSON error
+//! shape rather than an empty response. These are separate from how long graceful shutdown waits for in-flight requests
+//! (`app_server_graceful_shutdown_max_duration`).
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    api::errors::ApiError,
+    services::{app_server::serve::ShutdownDeadline, configs::ConfigReader},
+};
+
+/// How long requests to each group of routes can take, from the request_timeout_* config values
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestTimeouts {
+    /// The SPA, other static files, health probes and metrics
+    pub default: Duration,
+    /// API endpoints, other than search
+    pub api: Duration,
+    /// Search API endpoints
+    pub search: Duration,
+}
+
+impl RequestTimeouts {
+    pub fn from_config(reader: &mut ConfigReader) -> Self {
+        let timeouts = RequestTimeouts {
+            default: reader.get_seconds("request_timeout_default"),
+            api: reader.get_seconds("request_timeout_api"),
+            search: reader.get_seconds("request_timeout_search"),
+        };
+
+        for (key, timeout) in [
+            ("request_timeout_default", timeouts.default),
+            ("request_timeout_api", timeouts.api),
+            ("request_timeout_search", timeouts.search),
+        ] {
+            if timeout.is_zero() {
+                reader.add_problem(key, "must be at least 1 second");
+            }
+        }
+        timeouts
+    }
+}
+
+/// Middleware that limits how long requests can take. Add it to a group of routes with:
+/// `.route_layer(middleware::from_fn_with_state(timeouts.api, timeouts::limit))`
+pub async fn limit(State(timeout): State<Duration>, request: Request, next: Next) -> Response {
+    // Missing when the router isn't served by our serve loop, e.g. in tests
+    let shutdown_deadline: Option<ShutdownDeadline> =
+        request.extensions().get::<ShutdownDeadline>().cloned();
+    let shutdown_deadline_reached = async move {
+        match shutdown_deadline {
+            Some(shutdown_deadline) => shutdown_deadline.reached().await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        response = tokio::time::timeout(timeout, next.run(request)) => match response {
+            Ok(response) => response,
+            Err(_) => ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("The request didn't complete within {timeout:?}"),
+            )
+            .into_response(),
+        },
+        _ = shutdown_deadline_reached => ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The server is shutting down, please try again",
+        )
+        .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::api::resources::ErrorResponse;
+    use axum::{middleware, routing::get, Router};
+    use axum_test::TestServer;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn timeouts_limit() {
+        let router: Router = Router::new()
+            .route("/fast", get(|| async { "ok" }))
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "ok"
+                }),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                Duration::from_millis(100),
+                limit,
+            ));
+        let server = TestServer::new(router).unwrap();
+
+        assert_eq!(server.get("/fast").await.text(), "ok");
+
+        let response = server.get("/slow").await;
+        assert_eq!(response.status_code(), StatusCode::GATEWAY_TIMEOUT);
+        let error: ErrorResponse = response.json::<ErrorResponse>();
+        assert_eq!(error.status, 504);
+        assert!(error.message.contains("100ms"), "{}", error.message);
+    }
+}
@@ -1,14 +1,180 @@
 //! Provides utilities to initialize tracing and logging and provide functions to interact with it.
+//!
+//! Logs go to stdout and, when `log_file_dir` is set, to rolling log files, each with its own filter and format (see
+//! [`logs`]). Their filters can be changed while the app is running from our admin API (see [`filters`]).
+//!
+//! Besides logging to stdout, spans (for HTTP requests, DB queries and Search calls) can be exported to an
+//! OpenTelemetry collector over OTLP by setting `otel_exporter_otlp_endpoint`. Incoming W3C `traceparent` headers are
+//! honored, so our spans join the caller's trace.
 
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer};
+use axum::http::HeaderMap;
+use colored::Colorize;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{TraceContextExt, TracerProvider as _},
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::{level_filters::LevelFilter, Span};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+use super::configs::AppConfig;
+use filters::{LogFilters, ReloadableFilter};
+use logs::BoxedLayer;
+
+pub mod filters;
+pub mod logs;
+pub mod redact;
+
+/// Keeps trace export and log file writing running. Dropping it flushes any spans and log lines that haven't been
+/// written yet, so hold onto it until the app exits.
+#[must_use = "Dropping the guard stops trace export and log file writing"]
+pub struct TracingGuard {
+    tracer_provider: Option<SdkTracerProvider>,
+    _log_file_guard: Option<WorkerGuard>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = self.tracer_provider.take() {
+            if let Err(err) = tracer_provider.shutdown() {
+                eprintln!("Failed to flush traces on shutdown: {err}");
+            }
+        }
+    }
+}
 
 /// Initialize tracing services (which supports logging and other related things)
-pub fn init_tracing() {
-    let stdout_log = fmt::layer();
+pub fn init_tracing(app_config: &AppConfig) -> TracingGuard {
+    let (stdout_layer, stdout_filter_handle) = logs::build_stdout_layer(&app_config.log_stdout);
+    let mut layers: Vec<BoxedLayer> = vec![stdout_layer];
+    let mut filters: Vec<ReloadableFilter> = vec![ReloadableFilter::new(
+        "stdout",
+        &app_config.log_stdout.filter,
+        stdout_filter_handle,
+    )];
+
+    let log_file_guard: Option<WorkerGuard> = app_config.log_file.as_ref().map(|log_file_config| {
+        let (file_layer, file_filter_handle, guard) = logs::build_file_layer(log_file_config)
+            .unwrap_or_else(|err| {
+                panic!(
+                    "Failed to open log files in {}: {err}",
+                    log_file_config.dir.display()
+                )
+            });
+        layers.push(file_layer);
+        filters.push(ReloadableFilter::new(
+            "file",
+            &log_file_config.output.filter,
+            file_filter_handle,
+        ));
+        guard
+    });
+
+    let tracer_provider: Option<SdkTracerProvider> = init_tracer_provider(app_config);
+    // Our spans are at INFO, which also keeps the exporter's own (TRACE level) HTTP client spans from being exported
+    if let Some(tracer_provider) = &tracer_provider {
+        layers.push(
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer_provider.tracer(env!("CARGO_PKG_NAME")))
+                .with_filter(LevelFilter::INFO)
+                .boxed(),
+        );
+    }
+
+    tracing_subscriber::registry().with(layers).init();
+    filters::init_log_filters(LogFilters::new(filters));
+
+    TracingGuard {
+        tracer_provider,
+        _log_file_guard: log_file_guard,
+    }
+}
+
+/// Creates the provider that exports spans to an OpenTelemetry collector, or returns None if there's no collector
+/// configured. A misconfigured exporter is logged and skipped, rather than stopping the app from starting.
+fn init_tracer_provider(app_config: &AppConfig) -> Option<SdkTracerProvider> {
+    let endpoint: &str = app_config.otel_exporter_otlp_endpoint.as_deref()?;
+
+    let exporter: SpanExporter = match SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(err) => {
+            eprintln!(
+                "{} {endpoint}: {err}",
+                "Trace export is off, failed to create an exporter for".yellow()
+            );
+            return None;
+        }
+    };
+
+    let tracer_provider: SdkTracerProvider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(app_config.otel_service_name.clone())
+                .build(),
+        )
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(tracer_provider.clone());
+
+    Some(tracer_provider)
+}
+
+/// Makes the span a child of the trace in the request's `traceparent` header, if there is one. Does nothing when
+/// trace export is off.
+pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    let parent_context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+
+    if parent_context.span().span_context().is_remote() {
+        // Only fails when the span isn't recorded by OpenTelemetry, i.e. export is off
+        let _ = span.set_parent(parent_context);
+    }
+}
+
+/// Reads trace context from HTTP headers
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use axum::http::HeaderValue;
+    use opentelemetry::propagation::TextMapPropagator;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tracing_extract_traceparent() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
 
-    tracing_subscriber::registry()
-        .with(Layer::with_filter(stdout_log, LevelFilter::TRACE))
-        // We can andd more layers here with different filter levels to send to log files, metrics service, etc.
-        .init();
+        let context = TraceContextPropagator::new().extract(&HeaderExtractor(&headers));
+        assert_eq!(
+            context.span().span_context().trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert!(context.span().span_context().is_remote());
+    }
 }
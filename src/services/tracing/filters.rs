@@ -0,0 +1,275 @@
+//! Lets the filters of our log outputs be changed while the app is running, e.g. to turn on debug logs for one module
+//! while investigating a production issue, without restarting. A change can be given a time-to-live, after which the
+//! output reverts to the filter it was configured with, so a forgotten debug filter doesn't keep flooding the logs.
+use std::{
+    fmt,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::api::resources::LogFilter;
+
+/// Handle to replace the filter of one of our log outputs
+pub type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+static LOG_FILTERS: OnceLock<LogFilters> = OnceLock::new();
+
+/// Makes the filters available to [`get_log_filters`]. Called once, when tracing is initialized.
+pub fn init_log_filters(log_filters: LogFilters) {
+    if LOG_FILTERS.set(log_filters).is_err() {
+        panic!("Log filters were already initialized");
+    }
+}
+
+/// Returns the filters of our log outputs. There are none when tracing wasn't initialized, e.g. in tests.
+pub fn get_log_filters() -> &'static LogFilters {
+    LOG_FILTERS.get_or_init(LogFilters::default)
+}
+
+/// Reasons a log filter can't be changed
+#[derive(Debug)]
+pub enum LogFilterError {
+    UnknownOutput(String),
+    /// The directives aren't valid `RUST_LOG` syntax, or the time-to-live is out of range
+    InvalidFilter(String),
+    /// The subscriber holding the filter is gone
+    Reload(String),
+}
+
+impl fmt::Display for LogFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFilterError::UnknownOutput(output) => write!(f, "Unknown log output \"{output}\""),
+            LogFilterError::InvalidFilter(message) => write!(f, "Invalid log filter: {message}"),
+            LogFilterError::Reload(message) => write!(f, "Failed to change log filter: {message}"),
+        }
+    }
+}
+
+/// The filter of a single log output, e.g. stdout
+pub struct ReloadableFilter {
+    output: &'static str,
+    default_directives: String,
+    handle: FilterHandle,
+    active: Mutex<ActiveFilter>,
+}
+
+struct ActiveFilter {
+    directives: String,
+    expires_at: Option<DateTime<Utc>>,
+    /// Bumped on every change, so a pending revert can tell it's been superseded
+    generation: u64,
+}
+
+impl ReloadableFilter {
+    /// The directives are the output's configured filter, which it reverts to
+    pub fn new(output: &'static str, default_directives: &str, handle: FilterHandle) -> Self {
+        ReloadableFilter {
+            output,
+            default_directives: default_directives.to_string(),
+            handle,
+            active: Mutex::new(ActiveFilter {
+                directives: default_directives.to_string(),
+                expires_at: None,
+                generation: 0,
+            }),
+        }
+    }
+
+    fn to_resource(&self, active: &ActiveFilter) -> LogFilter {
+        LogFilter {
+            output: self.output.to_string(),
+            directives: active.directives.clone(),
+            default_directives: self.default_directives.clone(),
+            expires_at: active.expires_at,
+        }
+    }
+
+    /// Replaces the filter, returning the new state and its generation
+    fn apply(
+        &self,
+        directives: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(LogFilter, u64), LogFilterError> {
+        let mut active = self.active.lock().unwrap();
+        self.apply_locked(&mut active, directives, expires_at)
+    }
+
+    fn apply_locked(
+        &self,
+        active: &mut ActiveFilter,
+        directives: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(LogFilter, u64), LogFilterError> {
+        let env_filter: EnvFilter = EnvFilter::try_new(directives)
+            .map_err(|err| LogFilterError::InvalidFilter(err.to_string()))?;
+
+        self.handle
+            .reload(env_filter)
+            .map_err(|err| LogFilterError::Reload(err.to_string()))?;
+        active.directives = directives.to_string();
+        active.expires_at = expires_at;
+        active.generation += 1;
+
+        Ok((self.to_resource(active), active.generation))
+    }
+
+    /// Reverts to the default filter, unless the filter was changed again since the generation
+    fn revert_if_unchanged(&self, generation: u64) {
+        // Held throughout, so a change made at the same time isn't reverted
+        let mut active = self.active.lock().unwrap();
+        if active.generation != generation {
+            return;
+        }
+
+        let result = self.apply_locked(&mut active, &self.default_directives, None);
+        drop(active);
+        match result {
+            Ok(_) => tracing::warn!(
+                "Log filter for {} expired, reverted to \"{}\"",
+                self.output,
+                self.default_directives
+            ),
+            Err(err) => tracing::error!("Log filter for {} failed to revert: {err}", self.output),
+        }
+    }
+}
+
+/// The filters of all our log outputs
+#[derive(Default)]
+pub struct LogFilters {
+    filters: Vec<Arc<ReloadableFilter>>,
+}
+
+impl LogFilters {
+    pub fn new(filters: Vec<ReloadableFilter>) -> Self {
+        LogFilters {
+            filters: filters.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    pub fn get_all(&self) -> Vec<LogFilter> {
+        self.filters
+            .iter()
+            .map(|filter| filter.to_resource(&filter.active.lock().unwrap()))
+            .collect()
+    }
+
+    /// Replaces an output's filter. With a time-to-live, the output reverts to its default filter once it passes
+    /// (unless the filter is changed again before then). Needs to be called within a Tokio runtime.
+    pub fn set(
+        &self,
+        output: &str,
+        directives: &str,
+        ttl: Option<Duration>,
+    ) -> Result<LogFilter, LogFilterError> {
+        let filter: &Arc<ReloadableFilter> = self.find(output)?;
+        let expires_at: Option<DateTime<Utc>> = ttl
+            .map(|ttl| {
+                TimeDelta::from_std(ttl)
+                    .ok()
+                    .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+                    .ok_or_else(|| {
+                        LogFilterError::InvalidFilter(format!("time-to-live {ttl:?} is too long"))
+                    })
+            })
+            .transpose()?;
+
+        let (log_filter, generation) = filter.apply(directives, expires_at)?;
+
+        if let Some(ttl) = ttl {
+            let filter: Arc<ReloadableFilter> = Arc::clone(filter);
+            tokio::spawn(async move {
+                tokio::time::sleep(ttl).await;
+                filter.revert_if_unchanged(generation);
+            });
+        }
+
+        Ok(log_filter)
+    }
+
+    /// Reverts an output to its default filter
+    pub fn reset(&self, output: &str) -> Result<LogFilter, LogFilterError> {
+        let filter: &Arc<ReloadableFilter> = self.find(output)?;
+        filter
+            .apply(&filter.default_directives, None)
+            .map(|(log_filter, _)| log_filter)
+    }
+
+    fn find(&self, output: &str) -> Result<&Arc<ReloadableFilter>, LogFilterError> {
+        self.filters
+            .iter()
+            .find(|filter| filter.output == output)
+            .ok_or_else(|| LogFilterError::UnknownOutput(output.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tracing_subscriber::{layer::SubscriberExt, Layer};
+
+    /// Counts the events that pass the filter
+    struct CountingLayer(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> Layer<S> for CountingLayer {
+        fn on_event(
+            &self,
+            _event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn filters_set_and_expire() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("warn"));
+        let subscriber = tracing_subscriber::registry()
+            .with(CountingLayer(Arc::clone(&count)).with_filter(filter));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let log_filters = LogFilters::new(vec![ReloadableFilter::new("stdout", "warn", handle)]);
+
+        tracing::debug!("filtered out");
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        let log_filter: LogFilter = log_filters
+            .set("stdout", "debug", Some(Duration::from_millis(50)))
+            .unwrap();
+        assert_eq!(log_filter.directives, "debug");
+        assert!(log_filter.expires_at.is_some());
+        tracing::debug!("logged");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(log_filters.get_all()[0].directives, "warn");
+        // The revert itself is logged as a warning
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        tracing::debug!("filtered out again");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn filters_errors() {
+        let (_filter, handle) = reload::Layer::<EnvFilter, Registry>::new(EnvFilter::new("warn"));
+        let log_filters = LogFilters::new(vec![ReloadableFilter::new("stdout", "warn", handle)]);
+
+        assert!(matches!(
+            log_filters.set("file", "debug", None),
+            Err(LogFilterError::UnknownOutput(_))
+        ));
+        assert!(matches!(
+            log_filters.set("stdout", "info,sqlx=loud", None),
+            Err(LogFilterError::InvalidFilter(_))
+        ));
+        assert_eq!(log_filters.get_all()[0].directives, "warn");
+    }
+}
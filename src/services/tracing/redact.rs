@@ -0,0 +1,100 @@
+//! Keeps credentials out of our logs by redacting the values of sensitive fields (e.g. `password` or
+//! `authorization`), both on events and the spans they're in. Every log output built by [`logs`](super::logs) goes
+//! through this, so a stray `tracing::debug!(?headers.authorization)` doesn't leak a credential.
+//!
+//! Values are only redacted by field name, so credentials should also be held as a
+//! [`Secret`](crate::services::configs::secret::Secret), which is redacted however it's logged.
+use std::fmt;
+
+use serde_json::Value;
+use tracing::{field::Field, Event, Subscriber};
+use tracing_subscriber::{
+    field::{delimited::Delimited, MakeExt},
+    fmt::{
+        format::{debug_fn, FieldFn, Writer},
+        FmtContext, FormatEvent, FormatFields,
+    },
+    registry::LookupSpan,
+};
+
+use crate::services::configs::secret::REDACTED;
+
+/// Parts of field names whose values are redacted, matched case-insensitively anywhere in the name
+const SENSITIVE_FIELDS: [&str; 9] = [
+    "password",
+    "secret",
+    "token",
+    "api_key",
+    "master_key",
+    "authorization",
+    "cookie",
+    "credential",
+    "database_url",
+];
+
+/// Whether a field's value needs to be redacted
+pub fn is_sensitive(field_name: &str) -> bool {
+    let field_name: String = field_name.to_lowercase();
+    SENSITIVE_FIELDS
+        .iter()
+        .any(|sensitive| field_name.contains(sensitive))
+}
+
+/// Formats the fields of text logs like the default formatter, but with sensitive values redacted
+pub type RedactedFields =
+    Delimited<&'static str, FieldFn<fn(&mut Writer<'_>, &Field, &dyn fmt::Debug) -> fmt::Result>>;
+
+pub fn redacted_fields() -> RedactedFields {
+    let format_field: fn(&mut Writer<'_>, &Field, &dyn fmt::Debug) -> fmt::Result =
+        |writer, field, value| match field.name() {
+            "message" => write!(writer, "{value:?}"),
+            name if is_sensitive(name) => write!(writer, "{name}={REDACTED}"),
+            name => write!(writer, "{name}={value:?}"),
+        };
+    debug_fn(format_field).delimited(" ")
+}
+
+/// Wraps the JSON log format, redacting sensitive values in the event's fields and its spans' fields
+pub struct RedactedJson<E>(pub E);
+
+impl<S, N, E> FormatEvent<S, N> for RedactedJson<E>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    E: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut line: String = String::new();
+        self.0.format_event(ctx, Writer::new(&mut line), event)?;
+
+        match serde_json::from_str::<Value>(&line) {
+            Ok(mut value) => {
+                redact_json(&mut value);
+                writeln!(writer, "{value}")
+            }
+            // Never expected from the JSON format, but better to log the line than lose it
+            Err(_) => writer.write_str(&line),
+        }
+    }
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                if is_sensitive(key) {
+                    *value = Value::String(String::from(REDACTED));
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
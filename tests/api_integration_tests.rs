@@ -3,7 +3,21 @@
 //! to show:
 //!     1) how you'd test with our app server
 //!     2) verifies the app server's API Routes and MethodHandlers are setup properly that simulates  external calls to our APIs (maybe there's a better way to do this?)
-use rust_react_app_hello_world::api::{endpoints, resources::Player};
+use rust_react_app_hello_world::{
+    api::{
+        endpoints,
+        resources::{
+            ApiKeyCreateRequest, CreatedApiKey, ErrorResponse, LogFilterUpdateRequest, Player,
+            ReadinessReport,
+        },
+    },
+    services::{
+        auth::{api_keys, oidc::OidcConfig, sessions, Principal, Scope, Subject},
+        configs::{secret::Secret, AppConfig},
+        health, metrics, request_id,
+        static_mounts::StaticMount,
+    },
+};
 
 mod test_utils;
 
@@ -66,3 +80,427 @@ async fn api_add_player(pool: sqlx::PgPool) {
     let returned_player: Player = response.json::<Player>();
     assert!(returned_player.id.is_some());
 }
+
+/// Validates a failed insert gets a JSON error echoing the caller's request ID, rather than the bare DB error
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_add_player_error(pool: sqlx::PgPool) {
+    let server = test_utils::get_test_server_with_app(pool);
+
+    let player_to_create = Player {
+        id: None,
+        number: 31,
+        username: String::from("rambo"),
+        email: Some(String::from("kurt@lakers.com")),
+        name: String::from("Kurt Rambis"),
+    };
+    let response = server
+        .put(endpoints::PLAYERS_API)
+        .json(&player_to_create)
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::CREATED);
+
+    // Usernames are unique
+    let response = server
+        .put(endpoints::PLAYERS_API)
+        .json(&player_to_create)
+        .add_header(
+            request_id::X_REQUEST_ID.clone(),
+            axum::http::HeaderValue::from_static("report-31"),
+        )
+        .await;
+    assert_eq!(
+        response.status_code(),
+        axum::http::StatusCode::INTERNAL_SERVER_ERROR
+    );
+
+    let error: ErrorResponse = response.json::<ErrorResponse>();
+    assert_eq!(error.status, 500);
+    assert_eq!(error.request_id.as_deref(), Some("report-31"));
+}
+
+/// Validates our admin APIs can't be called without credentials, or with an invalid API key
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_api_keys_require_credentials(pool: sqlx::PgPool) {
+    let server = test_utils::get_test_server_with_app(pool);
+
+    let response = server.get(endpoints::API_KEYS_ADMIN_API).await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::UNAUTHORIZED);
+
+    let response = server
+        .get(endpoints::API_KEYS_ADMIN_API)
+        .authorization_bearer("px_00000000_unknown")
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::UNAUTHORIZED);
+}
+
+/// Validates the caller's request ID is echoed in the response headers and error body
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_request_id(pool: sqlx::PgPool) {
+    let server = test_utils::get_test_server_with_app(pool);
+
+    let response = server
+        .get(endpoints::API_KEYS_ADMIN_API)
+        .add_header(
+            request_id::X_REQUEST_ID.clone(),
+            axum::http::HeaderValue::from_static("report-42"),
+        )
+        .await;
+    assert_eq!(response.header(&request_id::X_REQUEST_ID), "report-42");
+
+    let error: ErrorResponse = response.json::<ErrorResponse>();
+    assert_eq!(error.request_id.as_deref(), Some("report-42"));
+}
+
+/// Validates an API key's scopes are enforced, from creation through revocation
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_api_key_lifecycle(pool: sqlx::PgPool) {
+    let admin_key: CreatedApiKey = api_keys::create_api_key(&pool, "admin", &[Scope::Admin])
+        .await
+        .unwrap();
+    let server = test_utils::get_test_server_with_app(pool);
+
+    // Create a read-only key through the admin API
+    let response = server
+        .put(endpoints::API_KEYS_ADMIN_API)
+        .authorization_bearer(&admin_key.key)
+        .json(&ApiKeyCreateRequest {
+            name: String::from("ingest"),
+            scopes: vec![Scope::PlayersRead],
+        })
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::CREATED);
+    let read_key: CreatedApiKey = response.json::<CreatedApiKey>();
+
+    let response = server
+        .get(endpoints::PLAYERS_API)
+        .authorization_bearer(&read_key.key)
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::OK);
+
+    let response = server
+        .get(endpoints::API_KEYS_ADMIN_API)
+        .authorization_bearer(&read_key.key)
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::FORBIDDEN);
+
+    // Once revoked, the key is rejected
+    let response = server
+        .delete(format!("{}/{}", endpoints::API_KEYS_ADMIN_API, read_key.api_key.id).as_str())
+        .authorization_bearer(&admin_key.key)
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::OK);
+
+    let response = server
+        .get(endpoints::PLAYERS_API)
+        .authorization_bearer(&read_key.key)
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::UNAUTHORIZED);
+}
+
+/// Validates the log filters admin API is only open to admins. Tracing isn't initialized in tests, so there are no
+/// log outputs to change.
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_log_filters(pool: sqlx::PgPool) {
+    let admin_key: CreatedApiKey = api_keys::create_api_key(&pool, "admin", &[Scope::Admin])
+        .await
+        .unwrap();
+    let server = test_utils::get_test_server_with_app(pool);
+    let update = LogFilterUpdateRequest {
+        directives: String::from("debug"),
+        ttl_seconds: Some(60),
+    };
+
+    let response = server
+        .put(format!("{}/stdout", endpoints::LOG_FILTERS_ADMIN_API).as_str())
+        .json(&update)
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::UNAUTHORIZED);
+
+    let response = server
+        .put(format!("{}/stdout", endpoints::LOG_FILTERS_ADMIN_API).as_str())
+        .authorization_bearer(&admin_key.key)
+        .json(&update)
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::NOT_FOUND);
+    assert_eq!(
+        response.json::<ErrorResponse>().message,
+        "Unknown log output \"stdout\""
+    );
+}
+
+/// Validates a user's session cookie identifies them to our APIs
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_session_cookie(pool: sqlx::PgPool) {
+    let user_id: uuid::Uuid = sqlx::query_scalar(
+        "insert into app_user (email, scopes) values ('kurt@lakers.com', '{players:read}') returning id",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    let token: String =
+        sessions::create_session(&pool, user_id, std::time::Duration::from_secs(60))
+            .await
+            .unwrap();
+    let server = test_utils::get_test_server_with_app(pool);
+
+    let me_path: String = endpoints::build_auth_path("/me");
+    let response = server.get(&me_path).await;
+    assert_eq!(response.json::<Principal>().subject, Subject::Anonymous);
+
+    let response = server
+        .get(&me_path)
+        .add_header(
+            axum::http::header::COOKIE,
+            axum::http::HeaderValue::from_str(&format!("{}={}", sessions::SESSION_COOKIE, token))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::OK);
+    assert_eq!(response.json::<Principal>().subject, Subject::User(user_id));
+}
+
+/// Validates a login can only be completed by the browser that started it, i.e. that has its state cookie
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_login_callback_requires_state_cookie(pool: sqlx::PgPool) {
+    let mut config: AppConfig = AppConfig::load().unwrap();
+    config.oidc = Some(OidcConfig {
+        issuer_url: String::from("http://127.0.0.1:1"),
+        client_id: String::from("hello-world"),
+        client_secret: Secret::new("secret"),
+        redirect_url: String::from("http://127.0.0.1:3000/api/auth/callback"),
+    });
+    let server = test_utils::get_test_server_with_config(config, pool);
+
+    let callback_path: String = endpoints::build_auth_path("/callback?code=mock-code&state=victim");
+    let response = server.get(&callback_path).await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::BAD_REQUEST);
+    assert!(response
+        .json::<ErrorResponse>()
+        .message
+        .contains("another browser"));
+
+    // Another login's state is no better, and the browser's state cookie is removed either way
+    let response = server
+        .get(&callback_path)
+        .add_header(
+            axum::http::header::COOKIE,
+            axum::http::HeaderValue::from_str(&format!(
+                "{}=attacker",
+                sessions::LOGIN_STATE_COOKIE
+            ))
+            .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::BAD_REQUEST);
+    assert!(response
+        .header(axum::http::header::SET_COOKIE)
+        .to_str()
+        .unwrap()
+        .starts_with(&format!("{}=;", sessions::LOGIN_STATE_COOKIE)));
+}
+
+/// Validates pages outside our APIs get the security headers. Only the SPA's bootstrap file gets the nonce based
+/// policy, with the nonce added to its scripts.
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_security_headers(pool: sqlx::PgPool) {
+    let mut config: AppConfig = AppConfig::load().unwrap();
+    config.spa_fallback_url = String::from("sample_page.html");
+    let server = test_utils::get_test_server_with_config(config, pool);
+
+    let response = server.get("/players/1").await;
+    let policy: String = response
+        .header(axum::http::header::CONTENT_SECURITY_POLICY)
+        .to_str()
+        .unwrap()
+        .to_string();
+    let nonce: &str = policy
+        .split_once("'nonce-")
+        .and_then(|(_, policy)| policy.split_once('\''))
+        .unwrap()
+        .0;
+    assert!(response
+        .text()
+        .contains(&format!("<script nonce=\"{nonce}\">window.__APP_CONFIG__")));
+    assert_eq!(
+        response.header(axum::http::header::CACHE_CONTROL),
+        "no-store"
+    );
+
+    // Static mounts keep their own Cache-Control, since their documents don't have a nonce
+    let response = server.get("/other-page").await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::OK);
+    assert_eq!(
+        response.header(axum::http::header::CACHE_CONTROL),
+        "no-cache"
+    );
+    assert!(!response
+        .header(axum::http::header::CONTENT_SECURITY_POLICY)
+        .to_str()
+        .unwrap()
+        .contains("'nonce-"));
+    assert_eq!(
+        response.header(axum::http::header::X_CONTENT_TYPE_OPTIONS),
+        "nosniff"
+    );
+}
+
+/// Validates a static mount's HTML is allowed to run its own scripts, which don't have a nonce
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_static_mount_scripts(pool: sqlx::PgPool) {
+    let dir: std::path::PathBuf =
+        std::env::temp_dir().join(format!("docs_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("index.html"),
+        "<html><head><script src=\"/docs/app.js\"></script></head></html>",
+    )
+    .unwrap();
+    std::fs::write(dir.join("app.js"), "console.log('docs');").unwrap();
+
+    let mut config: AppConfig = AppConfig::load().unwrap();
+    config.static_mounts.push(StaticMount {
+        path: String::from("/docs"),
+        source: dir.clone(),
+        cache_control: axum::http::HeaderValue::from_static("public, max-age=300"),
+        directory_listing: false,
+        fallback: None,
+    });
+    let server = test_utils::get_test_server_with_config(config, pool);
+
+    let response = server.get("/docs/").await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::OK);
+    assert!(response.text().contains("<script src=\"/docs/app.js\">"));
+    // Without a script-src, scripts fall back to default-src, which allows our origin
+    let policy: String = response
+        .header(axum::http::header::CONTENT_SECURITY_POLICY)
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(policy.contains("default-src 'self'"), "{policy}");
+    assert!(!policy.contains("script-src"), "{policy}");
+    assert_eq!(
+        response.header(axum::http::header::CACHE_CONTROL),
+        "public, max-age=300"
+    );
+
+    let response = server.get("/docs/app.js").await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::OK);
+    assert_eq!(response.text(), "console.log('docs');");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Validates our health probes are served without credentials, with a breakdown of the readiness checks
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_health_probes(pool: sqlx::PgPool) {
+    let server = test_utils::get_test_server_with_app(pool);
+
+    let response = server.get(health::LIVENESS_PATH).await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::OK);
+
+    // Whether we're ready depends on Search and the SPA build being available, but the database always is
+    let report: ReadinessReport = server
+        .get(health::READINESS_PATH)
+        .await
+        .json::<ReadinessReport>();
+    assert!(report
+        .checks
+        .iter()
+        .any(|check| check.name == "database" && check.healthy));
+}
+
+/// Validates requests are counted by route template in our Prometheus metrics
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_metrics(pool: sqlx::PgPool) {
+    let server = test_utils::get_test_server_with_app(pool);
+
+    server.get(endpoints::PLAYERS_API).await;
+
+    let response = server.get(metrics::METRICS_PATH).await;
+    assert_eq!(response.status_code(), axum::http::StatusCode::OK);
+    assert!(response
+        .text()
+        .contains(r#"http_requests_total{method="GET",route="/api/players",status="200"}"#));
+}
+
+/// Validates operational routes are only served on the admin listener when it's configured
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_admin_listener(pool: sqlx::PgPool) {
+    let (app_server, admin_server) = test_utils::get_test_servers_with_admin(pool);
+
+    // Unknown paths on the app server fall through to the SPA, so check the operational routes' responses are missing.
+    // Unknown API paths get a 404.
+    assert!(!app_server
+        .get(health::LIVENESS_PATH)
+        .await
+        .text()
+        .contains("status"));
+    assert!(!app_server
+        .get(metrics::METRICS_PATH)
+        .await
+        .text()
+        .contains("http_requests_total"));
+    assert_eq!(
+        app_server
+            .get(endpoints::API_KEYS_ADMIN_API)
+            .await
+            .status_code(),
+        axum::http::StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        app_server.get(endpoints::PLAYERS_API).await.status_code(),
+        axum::http::StatusCode::OK
+    );
+
+    assert_eq!(
+        admin_server.get(health::LIVENESS_PATH).await.status_code(),
+        axum::http::StatusCode::OK
+    );
+    assert_eq!(
+        admin_server.get(metrics::METRICS_PATH).await.status_code(),
+        axum::http::StatusCode::OK
+    );
+    // Admin APIs still need credentials on the admin listener
+    assert_eq!(
+        admin_server
+            .get(endpoints::API_KEYS_ADMIN_API)
+            .await
+            .status_code(),
+        axum::http::StatusCode::UNAUTHORIZED
+    );
+    // The public APIs aren't served there
+    assert_eq!(
+        admin_server.get(endpoints::PLAYERS_API).await.status_code(),
+        axum::http::StatusCode::NOT_FOUND
+    );
+}
+
+/// Validates unmatched API requests get JSON errors, while browser navigation still gets the SPA
+#[sqlx::test(migrator = "rust_react_app_hello_world::DB_MIGRATOR")]
+async fn api_unmatched_routes(pool: sqlx::PgPool) {
+    // Any HTML document stands in for the SPA's bootstrap file, which isn't built for tests
+    let mut config: AppConfig = AppConfig::load().unwrap();
+    config.spa_fallback_url = String::from("sample_page.html");
+    let server = test_utils::get_test_server_with_config(config, pool);
+
+    for path in ["/api/playrs", "/api", "/api/players/1/stats"] {
+        let response = server.get(path).await;
+        assert_eq!(
+            response.status_code(),
+            axum::http::StatusCode::NOT_FOUND,
+            "{path}"
+        );
+        assert_eq!(response.json::<ErrorResponse>().status, 404, "{path}");
+    }
+
+    let response = server.delete(endpoints::PLAYERS_API).await;
+    assert_eq!(
+        response.status_code(),
+        axum::http::StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert_eq!(response.json::<ErrorResponse>().status, 405);
+    assert_eq!(response.header(axum::http::header::ALLOW), "GET,HEAD,PUT");
+
+    let response = server.get("/players/1").await;
+    assert!(response.text().contains("window.__APP_CONFIG__"));
+}
@@ -2,11 +2,39 @@
 //! Follows the Rust convention that this file needs to be in a sub-folder and named "mod.rs":
 //! https://doc.rust-lang.org/book/ch11-03-test-organization.html#submodules-in-integration-tests
 
+use std::sync::Arc;
+
 use axum_test::TestServer;
-use rust_react_app_hello_world::services::{self, search};
+use rust_react_app_hello_world::services::{
+    self, app_server::AppState, configs::AppConfig, search,
+};
 
 pub fn get_test_server_with_app(pool: sqlx::PgPool) -> axum_test::TestServer {
-    let router: axum::Router =
-        services::app_server::init_router(pool, search::get_client().unwrap());
+    get_test_server_with_config(AppConfig::load().unwrap(), pool)
+}
+
+/// Returns a test server for the app with a config changed from the defaults
+pub fn get_test_server_with_config(config: AppConfig, pool: sqlx::PgPool) -> TestServer {
+    let router: axum::Router = services::app_server::init_router(build_app_state(config, pool));
     TestServer::new(router).unwrap()
 }
+
+/// Returns test servers for the app and for the admin listener, as when `admin_server_url` is set
+pub fn get_test_servers_with_admin(pool: sqlx::PgPool) -> (TestServer, TestServer) {
+    let mut config: AppConfig = AppConfig::load().unwrap();
+    config.admin_server_url = Some(String::from("127.0.0.1:0"));
+    let app_state: AppState = build_app_state(config, pool);
+
+    let app_server = TestServer::new(services::app_server::init_router(app_state.clone())).unwrap();
+    let admin_server = TestServer::new(services::app_server::init_admin_router(app_state)).unwrap();
+    (app_server, admin_server)
+}
+
+fn build_app_state(config: AppConfig, pool: sqlx::PgPool) -> AppState {
+    let search_client = search::get_client(&config).unwrap();
+    AppState {
+        config: Arc::new(config),
+        db_pool: pool,
+        search_client,
+    }
+}